@@ -0,0 +1,121 @@
+// Code for generating an HTML fragment, the web-native counterpart to the image
+
+use two_face::re_exports::syntect::highlighting::{Color, FontStyle, Theme};
+use two_face::re_exports::syntect::parsing::SyntaxSet;
+use two_face::re_exports::syntect::util::LinesWithEndings;
+
+use crate::svg::{background, highlight, text_foreground};
+use crate::utils::{boost_contrast, ensure_contrast};
+use crate::{Colors, FontConfig, Input, Layout};
+
+/// Renders the input as a `<pre>` with a styled `<span>` per token, colored the same way the image's code is.
+pub(crate) fn code_to_html(
+    theme: &Theme,
+    ss: &SyntaxSet,
+    source: &Input,
+    font: &FontConfig,
+    colors: &Colors,
+    layout: &Layout,
+) -> String {
+    let theme_bg = theme.settings.background.unwrap_or(Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    });
+    let bg = background(theme_bg, colors);
+    let fg = theme.settings.foreground.unwrap();
+    let text_fg = text_foreground(theme, colors);
+
+    let syntax = ss
+        .find_syntax_by_token(&source.ext)
+        .unwrap_or_else(|| ss.find_syntax_plain_text());
+    let source_lines: Vec<&str> = if layout.no_trailing_style {
+        source.contents.lines().collect()
+    } else {
+        LinesWithEndings::from(&source.contents).collect()
+    };
+    let lines = highlight(
+        theme,
+        ss,
+        syntax,
+        &source_lines,
+        &source.continued,
+        colors,
+        layout,
+    );
+
+    let mut html = format!(
+        "<pre style=\"background-color:{};color:{};font-family:'{}',monospace;font-size:{}px;padding:0.5em;tab-size:{}\"><code>",
+        css_color(bg),
+        css_color(text_fg),
+        escape(&font.family),
+        font.size,
+        layout.tab_width
+    );
+
+    for line in lines {
+        for (style, segment) in line {
+            // Line endings are part of the last segment with the newlines syntaxes
+            let (text, ending) = segment.split_at(segment.trim_end_matches(['\r', '\n']).len());
+            if !text.is_empty() {
+                let unstyled = style.foreground == fg && style.font_style.is_empty();
+                let color = if unstyled { text_fg } else { style.foreground };
+                let color = match colors.contrast_boost {
+                    Some(factor) => boost_contrast(color, bg, factor),
+                    None => color,
+                };
+                let color = match colors.min_contrast {
+                    Some(min) => ensure_contrast(color, bg, min).unwrap_or(color),
+                    None => color,
+                };
+
+                let mut css = format!("color:{}", css_color(color));
+                if style.font_style.contains(FontStyle::BOLD) {
+                    css.push_str(";font-weight:bold");
+                }
+                if style.font_style.contains(FontStyle::ITALIC) {
+                    css.push_str(";font-style:italic");
+                }
+                if style.font_style.contains(FontStyle::UNDERLINE) {
+                    css.push_str(";text-decoration:underline");
+                }
+                if style.background != theme_bg {
+                    css.push_str(&format!(
+                        ";background-color:{}",
+                        css_color(style.background)
+                    ));
+                }
+
+                html.push_str(&format!("<span style=\"{}\">{}</span>", css, escape(text)));
+            }
+            html.push_str(ending);
+        }
+        // The nonewlines syntaxes leave it to us to break the lines
+        if layout.no_trailing_style {
+            html.push('\n');
+        }
+    }
+
+    html.push_str("</code></pre>\n");
+    html
+}
+
+/// A hex color for CSS, with the alpha only when there is some transparency.
+fn css_color(c: Color) -> String {
+    if c.a == 0xFF {
+        format!("#{:02X}{:02X}{:02X}", c.r, c.g, c.b)
+    } else {
+        format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a)
+    }
+}
+
+/// Escapes the characters HTML would otherwise read as markup, quotes included so it's safe in
+/// attributes too.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
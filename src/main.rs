@@ -1,47 +1,98 @@
 use anyhow::Error;
 use anyhow::Result;
-use clap::Parser;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
 use core::fmt;
 use file_format::FileFormat;
 use fontdue::Font;
 use fs_err as fs;
-use image::ImageError;
-use lazy_static::lazy_static;
-use log::error;
-use resvg;
-use std::io;
-use std::path::PathBuf;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ColorType, ImageEncoder, ImageError};
+use log::{error, info, warn};
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
-use tiny_skia;
-use tiny_skia_path;
+use two_face::re_exports::syntect::dumps::{dump_to_file, dump_to_uncompressed_file};
+use two_face::re_exports::syntect::dumps::{from_dump_file, from_uncompressed_dump_file};
+use two_face::re_exports::syntect::highlighting::ScopeSelectors;
+use two_face::re_exports::syntect::highlighting::{Color, FontStyle, Highlighter, Theme, ThemeSet};
+use two_face::re_exports::syntect::parsing::{Scope, SyntaxReference, SyntaxSet};
 use two_face::theme::{extra, LazyThemeSet};
-use usvg;
-use usvg::fontdb::Source;
+use usvg::fontdb::{Database, Family, Query, Source, Weight};
 
+mod html;
 mod svg;
 mod utils;
 
-use svg::{code_to_svg, get_dimensions};
-// The latin codes I know about. Compiled very ad-hoc, so if there are any missing please let me know. I would value some good advice here
-lazy_static! {
-    static ref LATIN_CODES: Vec<&'static str> = vec![
-        "aa", "af", "ay", "bi", "br", "bs", "ca", "ch", "co", "cs", "cy", "da", "de", "en", "eo",
-        "es", "et", "eu", "fi", "fj", "fo", "fr", "fur", "fy", "gd", "gl", "gv", "ho", "hr", "hu",
-        "ia", "id", "ie", "io", "is", "it", "ki", "kl", "la", "lb", "lt", "lv", "mg", "mh", "mt",
-        "nb", "nds", "nl", "nn", "no", "nr", "nso", "ny", "oc", "om", "pl", "pt", "rm", "ro", "se",
-        "sk", "sl", "sma", "smj", "smn", "so", "sq", "ss", "st", "sv", "sw", "tk", "tl", "tn",
-        "tr", "ts", "uz", "vo", "vot", "wa", "wen", "wo", "xh", "yap", "zu", "an", "crh", "csb",
-        "fil", "hsb", "ht", "jv", "kj", "ku-tr", "kwm", "lg", "li", "ms", "na", "ng", "pap-an",
-        "pap-aw", "rn", "rw", "sc", "sg", "sn", "su", "ty", "za", "agr", "ayc", "bem", "dsb",
-        "lij", "mfe", "mjw", "nhn", "niu", "sgs", "szl", "tpi", "unm", "wae", "yuw",
-    ];
-}
+use html::code_to_html;
+use svg::{code_to_svg, detect_language, get_dimensions, serialize, sheet_svg};
+#[cfg(feature = "url")]
+use utils::fetch_url;
+use utils::{
+    added_lines, collapse_prose, decode, focus_on_match, git_diff, git_diff_files, invert_theme,
+    is_box_drawing, is_monospace, missing_glyphs, normalize_indent, prefers_dark, read_clipboard,
+    relative_luminance, removed_lines, replace_control_chars, select_lines, strip_ansi,
+    strip_trailing_whitespace, trim_pixmap, wrap_lines, write_atomic,
+};
+
+// Monospace families that reliably ship the box drawing and block element ranges
+const BOX_DRAWING_FALLBACKS: &[&str] = &[
+    "DejaVu Sans Mono",
+    "Menlo",
+    "Cascadia Mono",
+    "Consolas",
+    "Noto Sans Mono",
+];
+
+// Lines longer than this are refused unless something bounds the width, it's usually minified code
+const LONG_LINE_LIMIT: usize = 10_000;
+
+// Quality JPEG output is encoded at, high enough that text edges stay clean
+const JPEG_QUALITY: u8 = 90;
+
+// Side of each square in a --checkerboard-preview, in pixels
+const CHECKERBOARD_SQUARE: u32 = 8;
+
+// Inputs shorter than this highlight too quickly for progress to be useful
+const PROGRESS_MIN_LINES: usize = 5000;
+
+// How many characters without a glyph are named before the rest are just counted
+const MAX_MISSING_LISTED: usize = 10;
+
+// How many similar families a font-not-found error suggests
+const MAX_FONT_SUGGESTIONS: usize = 5;
+
+// A translucent red to pair with the default added-line green
+const DIFF_REMOVED_COLOR: rgb::RGBA8 = rgb::RGBA8 {
+    r: 0xBF,
+    g: 0x61,
+    b: 0x6A,
+    a: 0x40,
+};
 
 struct FontConfig {
+    /// Only ever measured, at `size` passed explicitly, so its own scale setting doesn't matter
     glyphs: Font,
+    family: String,
     data: Vec<u8>,
+    /// The SVG font-size in px, which every width and height is measured at
     size: f32,
+    weight: Option<u16>,
+    fallbacks: Vec<String>,
+    /// Multiplies the font's own line spacing
+    line_height: f32,
 }
 
 #[derive(Clone, Debug)]
@@ -49,11 +100,378 @@ struct Input {
     file_handler: Option<PathBuf>,
     contents: String,
     ext: String,
+    valid_utf8: bool,
+    // Rows that --wrap broke off the line above, highlighted as the same line
+    continued: Vec<Option<Continuation>>,
+}
+
+/// How a row broken off a longer line differs from the text it came from.
+#[derive(Clone, Debug, PartialEq)]
+struct Continuation {
+    /// Bytes of indentation put in front of the row
+    indent: usize,
+    /// What the break took out between this row and the one above, the spaces it was made at or
+    /// nothing for a mid-word break
+    dropped: String,
 }
 
+#[derive(Clone)]
 struct Colors {
+    background: Option<HexColor>,
+    foreground: Option<HexColor>,
+    style_overrides: Vec<StyleOverride>,
     background_alpha: Alpha,
     foreground_alpha: Alpha,
+    ruler: Option<rgb::RGBA8>,
+    bg_pattern: Option<rgb::RGBA8>,
+    text_glow: Option<rgb::RGBA8>,
+    zebra: Option<rgb::RGBA8>,
+    keyword: Option<rgb::RGBA8>,
+    diff_added: Option<rgb::RGBA8>,
+    outer: Option<rgb::RGBA8>,
+    min_contrast: Option<f32>,
+    contrast_boost: Option<f32>,
+    annotation: Option<rgb::RGBA8>,
+    selection: Option<rgb::RGBA8>,
+}
+
+#[derive(Clone)]
+struct Layout {
+    rulers: Vec<usize>,
+    size: Option<Dimensions>,
+    overflow: Overflow,
+    min_scale: Option<f32>,
+    aspect: Option<AspectRatio>,
+    viewbox: bool,
+    preserve_aspect: Option<PreserveAspect>,
+    columns: Option<usize>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    bg_pattern: Option<BgPattern>,
+    bg_pattern_spacing: f32,
+    text_glow_blur: f32,
+    zebra: bool,
+    progress: Option<ProgressFn>,
+    raw: bool,
+    keywords: Vec<String>,
+    tab_width: usize,
+    tab_stops: bool,
+    max_width_chars: Option<usize>,
+    truncate_middle: Option<usize>,
+    debug_grid: bool,
+    guides: bool,
+    diff_added: Option<Vec<usize>>,
+    vcenter: bool,
+    outer_margin: f32,
+    padding: f32,
+    radius: f32,
+    shadow: Option<ShadowOffset>,
+    shadow_blur: f32,
+    preserve_empty_last_line: bool,
+    show_line_endings: bool,
+    no_trailing_style: bool,
+    annotations: Vec<Annotation>,
+    show_whitespace: Option<ShowWhitespace>,
+    selection: Option<ByteRange>,
+    space_mode: SpaceMode,
+    chrome: Chrome,
+    window_title: Option<String>,
+    vignette: Option<Alpha>,
+    line_numbers: bool,
+    start_line: usize,
+    current_line: Option<usize>,
+    /// Line numbers as shown, which stand out while every other line fades back
+    highlight_lines: Vec<usize>,
+    relative_line_numbers: bool,
+    line_origins: Option<Vec<Option<usize>>>,
+    gutter_separator: bool,
+    scroll_shadows: bool,
+    text_align: TextAlign,
+    emphasize_first_line: Option<f32>,
+    path_header: bool,
+    path_root: Option<PathBuf>,
+    title: Option<String>,
+    title_align: TextAlign,
+}
+
+/// How far a render has got, for whoever is watching it
+#[derive(Debug, Clone, Copy)]
+enum RenderStage {
+    /// Highlighting is about to start on this many lines
+    Highlighting { total: usize },
+    /// Line `line` of `total` is highlighted, counting from 1
+    Highlighted { line: usize, total: usize },
+    /// The SVG document is complete
+    SvgBuilt,
+    /// The document has been drawn into pixels
+    Rasterized,
+}
+
+/// Told each stage of a render as it's reached, e.g. to drive a progress bar
+type ProgressFn = Rc<dyn Fn(RenderStage)>;
+
+impl Colors {
+    /// The colors as the flags give them
+    fn from_cli(cli: &Cli) -> Self {
+        Colors {
+            background: cli.bg_color,
+            foreground: cli.fg_color,
+            style_overrides: cli
+                .style_override
+                .iter()
+                .chain(&cli.plain_scopes)
+                .cloned()
+                .chain(
+                    cli.no_dim_comments
+                        .then(|| StyleOverride::plain("comment").expect("a valid scope")),
+                )
+                .collect(),
+            background_alpha: cli.bg_alpha,
+            foreground_alpha: cli.fg_alpha,
+            ruler: cli.ruler_color,
+            bg_pattern: cli.bg_pattern_color,
+            text_glow: cli.text_glow,
+            zebra: cli.zebra_color,
+            keyword: cli.keyword_color,
+            diff_added: cli.diff_added_color,
+            outer: cli.outer_color,
+            min_contrast: cli.min_contrast,
+            contrast_boost: cli.contrast_boost,
+            annotation: cli.annotation_color,
+            selection: cli.selection_color,
+        }
+    }
+}
+
+impl Layout {
+    /// The layout as the flags give it, before anything is worked out from the input
+    fn from_cli(cli: &Cli) -> Self {
+        Layout {
+            rulers: cli.ruler.clone(),
+            size: cli.size,
+            overflow: cli.overflow,
+            min_scale: None,
+            aspect: cli.aspect,
+            viewbox: cli.viewbox,
+            preserve_aspect: cli.preserve_aspect.clone(),
+            columns: cli.columns,
+            min_width: cli.min_width,
+            min_height: cli.min_height,
+            bg_pattern: cli.bg_pattern,
+            bg_pattern_spacing: cli.bg_pattern_spacing,
+            text_glow_blur: cli.text_glow_blur,
+            zebra: cli.zebra,
+            // Progress only makes sense when someone's watching the terminal
+            progress: (!cli.quiet && io::stderr().is_terminal())
+                .then(|| Rc::new(report_progress) as ProgressFn),
+            raw: cli.raw,
+            keywords: cli.highlight_keywords.clone(),
+            tab_width: usize::from(cli.tab_width),
+            tab_stops: cli.tab_stops,
+            max_width_chars: cli.max_width_chars.map(usize::from),
+            truncate_middle: cli.truncate_middle.map(usize::from),
+            debug_grid: cli.debug_grid,
+            guides: cli.guides,
+            diff_added: None,
+            vcenter: cli.vcenter,
+            outer_margin: cli.outer_margin,
+            padding: cli.padding,
+            radius: cli.radius,
+            shadow: cli.shadow.then_some(cli.shadow_offset),
+            shadow_blur: cli.shadow_blur,
+            preserve_empty_last_line: cli.preserve_empty_last_line,
+            show_line_endings: cli.show_line_endings,
+            no_trailing_style: cli.no_trailing_style,
+            annotations: cli.annotate.clone(),
+            show_whitespace: cli.show_whitespace,
+            selection: cli.select,
+            space_mode: cli.space_mode,
+            // --window is the macOS look, and a window title needs some bar to sit in
+            chrome: match (cli.window, cli.chrome, &cli.window_title) {
+                (true, _, _) => Chrome::Macos,
+                (false, Chrome::None, Some(_)) => Chrome::Plain,
+                (false, chrome, _) => chrome,
+            },
+            window_title: cli.window_title.clone(),
+            vignette: cli.vignette,
+            line_numbers: cli.line_numbers,
+            start_line: cli.start_line as usize,
+            current_line: cli.current_line.map(|line| line as usize),
+            highlight_lines: cli
+                .highlight_lines
+                .iter()
+                .flat_map(|range| range.start..=range.end)
+                .collect(),
+            relative_line_numbers: cli.relative_line_numbers,
+            line_origins: None,
+            gutter_separator: cli.gutter_separator,
+            scroll_shadows: cli.scroll_shadows,
+            text_align: cli.text_align,
+            emphasize_first_line: cli.emphasize_first_line.map(|ScaleFactor(f)| f),
+            path_header: cli.path_header,
+            path_root: cli.root.clone(),
+            title: cli.title.clone(),
+            title_align: cli.title_align,
+        }
+    }
+}
+
+/// A texture laid over the flat background color
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BgPattern {
+    Dots,
+    Grid,
+}
+
+/// Which whitespace gets a visible marker
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ShowWhitespace {
+    /// Only the leading indentation of each line
+    Indent,
+    /// Every space and tab
+    All,
+}
+
+/// How runs of spaces are kept from collapsing in the SVG
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum SpaceMode {
+    /// Mark each line `xml:space="preserve"`, keeping the plain spaces
+    #[default]
+    Preserve,
+    /// Swap spaces and tabs for no-break spaces, which no renderer collapses
+    Nbsp,
+}
+
+/// What happens to control characters like form feeds, which have no glyph of their own
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum ControlChars {
+    /// Remove them
+    Strip,
+    /// Swap each for its Unicode control picture, e.g. ␌ for a form feed
+    Show,
+    /// Leave them in, for the font to draw however it does
+    #[default]
+    Ignore,
+}
+
+/// Characters indentation is written with
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum IndentStyle {
+    Spaces,
+    Tabs,
+}
+
+/// The kind of file to write
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Png,
+    Svg,
+    /// Bare premultiplied RGBA8 pixels behind a width/height header
+    Raw,
+    /// A `<pre>` fragment with a styled span per token, for embedding in web pages
+    Html,
+    /// Lossy, with any transparency flattened over white
+    #[value(alias = "jpg")]
+    Jpeg,
+    /// Lossless, keeping transparency
+    Webp,
+}
+
+impl OutputFormat {
+    /// The format an output path's extension asks for, if it has one
+    fn from_extension(path: &Path) -> Result<Option<Self>, SiaError> {
+        let Some(ext) = path.extension() else {
+            return Ok(None);
+        };
+
+        let format = match ext.to_string_lossy().to_ascii_lowercase().as_str() {
+            "png" => OutputFormat::Png,
+            "svg" => OutputFormat::Svg,
+            "raw" => OutputFormat::Raw,
+            "html" | "htm" => OutputFormat::Html,
+            "jpg" | "jpeg" => OutputFormat::Jpeg,
+            "webp" => OutputFormat::Webp,
+            other => {
+                return Err(SiaError::InvalidConfig(format!(
+                    "can't write .{} files, pass --format to choose what to write",
+                    other
+                )))
+            }
+        };
+        Ok(Some(format))
+    }
+}
+
+/// How semi-transparent pixels are stored
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum AlphaMode {
+    /// Color channels already multiplied by alpha, as tiny-skia renders them
+    Premultiplied,
+    /// Color channels independent of alpha, what PNG viewers and most tools expect
+    Straight,
+}
+
+/// What to do when the content doesn't fit inside a fixed `--size`
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Overflow {
+    /// Crop whatever falls outside the canvas
+    Clip,
+    /// Shrink the content until it fits
+    Scale,
+    /// Refuse to render
+    Error,
+    /// Break only the lines too wide for the canvas onto continuation rows, cropping any excess height
+    Wrap,
+}
+
+/// How the two sides of --compare are arranged
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum CompareLayout {
+    /// Before on the left, after on the right
+    #[default]
+    Side,
+    /// Before above after
+    Stacked,
+}
+
+/// Where each line sits relative to the longest
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum TextAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// Window decoration drawn as a title bar above the code
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum Chrome {
+    /// macOS traffic lights
+    Macos,
+    /// Windows minimize, maximize and close buttons
+    Windows,
+    /// Just the bar
+    Plain,
+    /// No title bar
+    #[default]
+    None,
+}
+
+/// How content too wide for the canvas at --min-font-size is made to fit
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum MinFontFallback {
+    /// Break long lines at the column the canvas holds
+    #[default]
+    Wrap,
+    /// Grow the canvas to the width the lines need
+    Widen,
+}
+
+/// Whether a kind of element is drawn with antialiased edges
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Antialias {
+    On,
+    Off,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -86,6 +504,245 @@ impl FromStr for Dimensions {
     }
 }
 
+/// A note for the margin beside a line, written `12=needs refactor` with 1-based line numbers
+#[derive(Debug, Clone)]
+struct Annotation {
+    line: usize,
+    note: String,
+}
+
+impl FromStr for Annotation {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let (line, note) = s
+            .split_once('=')
+            .and_then(|(line, note)| Some((line.trim().parse().ok()?, note)))
+            .filter(|&(line, _)| line > 0)
+            .ok_or_else(|| {
+                SiaError::InvalidConfig(format!("annotation '{}' needs to be line=note", s))
+            })?;
+
+        Ok(Annotation {
+            line,
+            note: note.to_string(),
+        })
+    }
+}
+
+/// 1-based lines from `start` to `end` inclusive, written `10-12`, or `10` for just one
+#[derive(Debug, Clone, Copy)]
+struct LineRange {
+    start: usize,
+    end: usize,
+}
+
+impl FromStr for LineRange {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse = |v: &str| v.trim().parse::<usize>().ok().filter(|&n| n > 0);
+
+        match (parse(start), parse(end)) {
+            (Some(start), Some(end)) if start <= end => Ok(LineRange { start, end }),
+            _ => Err(SiaError::InvalidConfig(format!(
+                "line range '{}' needs to be start-end, counting from 1",
+                s
+            ))),
+        }
+    }
+}
+
+/// A position in pixels such as `40,40`, measured from the top left
+#[derive(Debug, Clone, Copy)]
+struct Offset {
+    x: i32,
+    y: i32,
+}
+
+impl FromStr for Offset {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let (x, y) = s
+            .split_once(',')
+            .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+            .ok_or_else(|| SiaError::InvalidConfig(format!("offset '{}' needs to be x,y", s)))?;
+
+        Ok(Offset { x, y })
+    }
+}
+
+/// How far a shadow falls from what casts it, in pixels such as `0x8`
+#[derive(Debug, Clone, Copy)]
+struct ShadowOffset {
+    x: f32,
+    y: f32,
+}
+
+impl FromStr for ShadowOffset {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let (x, y) = s
+            .split_once('x')
+            .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+            .ok_or_else(|| {
+                SiaError::InvalidConfig(format!("shadow offset '{}' needs to be XxY", s))
+            })?;
+
+        Ok(ShadowOffset { x, y })
+    }
+}
+
+/// A span of the input in bytes such as `120..164`, end exclusive
+#[derive(Debug, Clone, Copy)]
+struct ByteRange {
+    start: usize,
+    end: usize,
+}
+
+impl FromStr for ByteRange {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let (start, end) = s
+            .split_once("..")
+            .and_then(|(start, end)| Some((start.trim().parse().ok()?, end.trim().parse().ok()?)))
+            .filter(|&(start, end)| start <= end)
+            .ok_or_else(|| {
+                SiaError::InvalidConfig(format!("range '{}' needs to be start..end", s))
+            })?;
+
+        Ok(ByteRange { start, end })
+    }
+}
+
+/// A font size as given on the command line, in px (the default) or pt
+#[derive(Debug, Clone, Copy)]
+enum FontSize {
+    Px(f32),
+    Pt(f32),
+}
+
+impl FontSize {
+    /// Resolves the size to pixels at the given DPI
+    fn to_px(self, dpi: f32) -> f32 {
+        match self {
+            FontSize::Px(px) => px,
+            FontSize::Pt(pt) => pt * dpi / 72.0,
+        }
+    }
+}
+
+impl FromStr for FontSize {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let s = s.trim();
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<f32>()
+                .map_err(|_| SiaError::InvalidConfig("font size".into()))
+        };
+
+        if let Some(pt) = s.strip_suffix("pt") {
+            Ok(FontSize::Pt(parse(pt)?))
+        } else {
+            Ok(FontSize::Px(parse(s.strip_suffix("px").unwrap_or(s))?))
+        }
+    }
+}
+
+/// A render scale such as `2x` (the `x` is optional)
+#[derive(Debug, Clone, Copy)]
+struct ScaleFactor(f32);
+
+impl FromStr for ScaleFactor {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let s = s.trim();
+        let v: f32 = s
+            .strip_suffix('x')
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| SiaError::InvalidConfig("scale".into()))?;
+
+        if v <= 0.0 {
+            return Err(SiaError::InvalidConfig("scale".into()));
+        }
+
+        Ok(ScaleFactor(v))
+    }
+}
+
+/// Target width:height ratio, e.g. `1.91:1` or just `1.91`
+#[derive(Debug, Clone, Copy)]
+struct AspectRatio(f32);
+
+impl FromStr for AspectRatio {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let mut parts = s.split(':');
+
+        let w: f32 = parts
+            .next()
+            .and_then(|p| p.trim().parse().ok())
+            .ok_or_else(|| SiaError::InvalidConfig("aspect".into()))?;
+
+        let h: f32 = match parts.next() {
+            Some(p) => p
+                .trim()
+                .parse()
+                .map_err(|_| SiaError::InvalidConfig("aspect".into()))?,
+            None => 1.0,
+        };
+
+        if w <= 0.0 || h <= 0.0 || parts.next().is_some() {
+            return Err(SiaError::InvalidConfig("aspect".into()));
+        }
+
+        Ok(AspectRatio(w / h))
+    }
+}
+
+/// An SVG `preserveAspectRatio` value, e.g. `xMidYMid meet`, `xMinYMin slice` or `none`
+#[derive(Debug, Clone)]
+struct PreserveAspect(String);
+
+impl FromStr for PreserveAspect {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let invalid = || {
+            SiaError::InvalidConfig(format!(
+                "'{}' isn't a preserveAspectRatio, expected e.g. 'xMidYMid meet', 'xMidYMid slice' or 'none'",
+                s
+            ))
+        };
+        let mut parts = s.split_whitespace();
+
+        let align = parts.next().ok_or_else(invalid)?;
+        let aligned = ["xMin", "xMid", "xMax"]
+            .iter()
+            .flat_map(|x| ["YMin", "YMid", "YMax"].map(|y| format!("{}{}", x, y)))
+            .any(|valid| valid == align);
+        if !aligned && align != "none" {
+            return Err(invalid());
+        }
+
+        match (parts.next(), parts.next()) {
+            (None | Some("meet" | "slice"), None) => Ok(PreserveAspect(
+                s.split_whitespace().collect::<Vec<_>>().join(" "),
+            )),
+            _ => Err(invalid()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Alpha(f32);
 
@@ -114,6 +771,7 @@ impl fmt::Display for Alpha {
 }
 
 #[derive(Debug, Error)]
+#[allow(dead_code)]
 enum SiaError {
     #[error("I/O error: {0}")]
     Io(#[from] io::Error),
@@ -144,43 +802,250 @@ enum SiaError {
 
     #[error("Sia Error: {0}")]
     Message(String),
+
+    #[cfg(feature = "url")]
+    #[error("HTTP {status} fetching {url}")]
+    Http { url: String, status: u16 },
 }
 
 fn parse_to_input(s: &str) -> Result<Input, SiaError> {
+    // Piped input has no name to take an extension from, so it's sniffed like literal text
+    if s == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        let ext = guess_ext(&bytes);
+        return Ok(bytes_input(bytes, ext, None));
+    }
+
+    // Raw files and gists can be rendered straight from their URL
+    #[cfg(feature = "url")]
+    if s.starts_with("https://") || s.starts_with("http://") {
+        let bytes = fetch_url(s)?;
+
+        // Only the path says anything about the language, not the query or fragment
+        let path = s.split(['?', '#']).next().unwrap_or(s);
+        let ext = match path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.'))
+        {
+            Some((stem, extension)) if !stem.is_empty() && !extension.is_empty() => {
+                extension.to_string()
+            }
+            _ => guess_ext(&bytes),
+        };
+
+        return Ok(bytes_input(bytes, ext, None));
+    }
+
     // Convert to path
     let path = PathBuf::from(s);
 
+    // Otherwise the directory's path would be rendered as if it were the text
+    if path.is_dir() {
+        return Err(SiaError::InvalidConfig(format!(
+            "{} is a directory, pass a file inside it (or several with --concat)",
+            path.display()
+        )));
+    }
+
     // If it is a real path, use that information
     if path.exists() && path.is_file() {
+        let bytes = fs::read(&path)?;
+
         let ext: String;
         // The extension is the valuable piece of info here. No ext, we need to guess.
         if let Some(extension) = path.extension() {
             ext = extension.to_string_lossy().to_string();
         } else {
-            ext = FileFormat::from_file(&path)?.extension().to_string();
+            ext = guess_ext(&bytes);
         }
 
-        let contents = fs::read_to_string(&path)?;
+        Ok(bytes_input(bytes, ext, Some(path)))
+    } else {
+        Ok(literal_input(s))
+    }
+}
 
-        Ok(Input {
-            file_handler: Some(path),
-            ext,
-            contents,
-        })
+fn bytes_input(bytes: Vec<u8>, ext: String, file_handler: Option<PathBuf>) -> Input {
+    // Legacy encodings are only decoded once --encoding is known, so don't fail here
+    let valid_utf8 = std::str::from_utf8(&bytes).is_ok();
+    let mut contents = String::from_utf8_lossy(&bytes).into_owned();
+
+    // A leading BOM would otherwise render as a stray glyph
+    if let Some(stripped) = contents.strip_prefix('\u{FEFF}') {
+        contents = stripped.to_string();
+    }
+
+    Input {
+        file_handler,
+        ext,
+        contents,
+        valid_utf8,
+        continued: Vec::new(),
+    }
+}
+
+/// Decodes the input and reshapes it as asked, before anything gets measured.
+fn prepare_input(cli: &Cli, input: &mut Input) -> Result<(), Error> {
+    // Files are strict UTF-8 unless told otherwise
+    match (&input.file_handler, cli.encoding) {
+        (Some(path), Some(encoding)) => {
+            input.contents = decode(&fs::read(path)?, encoding);
+        }
+        (Some(path), None) if !input.valid_utf8 => {
+            return Err(SiaError::Parse(format!(
+                "{} isn't valid UTF-8, pass its --encoding",
+                path.display()
+            ))
+            .into());
+        }
+        _ => {}
+    }
+
+    // Already-colored terminal output would otherwise render its escape codes as text
+    if cli.strip_ansi {
+        input.contents = strip_ansi(&input.contents);
+    }
+
+    // After the ANSI codes, so stripping doesn't leave their escape-less remains behind
+    if !matches!(cli.control_chars, ControlChars::Ignore) {
+        input.contents = replace_control_chars(&input.contents, cli.control_chars);
+    }
+
+    // Narrow down to the region around the match before anything gets measured
+    if let Some(pattern) = &cli.focus_match {
+        input.contents =
+            focus_on_match(&input.contents, pattern, cli.focus_context).ok_or_else(|| {
+                SiaError::InvalidConfig(format!("no line matches --focus-match '{}'", pattern))
+            })?;
+    }
+
+    // Prose reads as paragraphs rather than the source's own line breaks
+    if cli.prose {
+        input.contents = collapse_prose(&input.contents);
+    }
+
+    // Before wrapping, so lines are measured with their final indentation
+    if let Some(style) = cli.normalize_indent {
+        let (normalized, mixed) =
+            normalize_indent(&input.contents, style, usize::from(cli.tab_width));
+        if mixed && !cli.quiet {
+            let to = match style {
+                IndentStyle::Spaces => "spaces",
+                IndentStyle::Tabs => "tabs",
+            };
+            eprintln!(
+                "Found both tab and space indentation, normalized it all to {}",
+                to
+            );
+        }
+        input.contents = normalized;
+    }
+
+    if let Some(columns) = cli.wrap {
+        wrap_input(input, columns);
+    }
+
+    // Trailing blanks still have an advance, so they'd widen the canvas for nothing
+    if cli.strip_trailing_whitespace {
+        input.contents = strip_trailing_whitespace(&input.contents);
+    }
+
+    Ok(())
+}
+
+fn literal_input(s: &str) -> Input {
+    // Treat input literally as UTF-8 text
+    // Can't help if it's not...
+    let ext = guess_ext(s.as_bytes());
+
+    Input {
+        file_handler: None,
+        contents: s.into(),
+        ext,
+        valid_utf8: true,
+        continued: Vec::new(),
+    }
+}
+
+/// Wraps the input's lines at `columns`, remembering which rows continue the line above.
+fn wrap_input(input: &mut Input, columns: usize) {
+    let (contents, continued) = wrap_lines(&input.contents, columns, &input.continued);
+    input.contents = contents;
+    input.continued = continued;
+}
+
+/// Highlights the input as --lang, whatever its extension or content suggested.
+fn force_language(cli: &Cli, syntaxes: &SyntaxSet, input: &mut Input) -> Result<(), SiaError> {
+    let Some(lang) = &cli.lang else {
+        return Ok(());
+    };
+
+    match syntaxes
+        .find_syntax_by_token(lang)
+        .or_else(|| syntaxes.find_syntax_by_name(lang))
+    {
+        Some(syntax) => {
+            input.ext = syntax.name.clone();
+            Ok(())
+        }
+        None => Err(SiaError::InvalidConfig(format!(
+            "no syntax for --lang '{}'",
+            lang
+        ))),
+    }
+}
+
+/// Points input whose language wasn't detected (leaving it plain text) at --fallback-language instead.
+fn fall_back_language(cli: &Cli, syntaxes: &SyntaxSet, input: &mut Input) {
+    let Some(name) = &cli.fallback_language else {
+        return;
+    };
+    let detected = syntaxes
+        .find_syntax_by_token(&input.ext)
+        .is_some_and(|syntax| syntax.name != syntaxes.find_syntax_plain_text().name);
+    if detected {
+        return;
+    }
+
+    // Syntaxes can be looked up by name wherever an extension is expected
+    match syntaxes
+        .find_syntax_by_name(name)
+        .or_else(|| syntaxes.find_syntax_by_token(name))
+    {
+        Some(syntax) => input.ext = syntax.name.clone(),
+        None => warn!("No syntax named '{}', falling back to plain text", name),
+    }
+}
+
+/// Guesses an extension from the content, settling on "txt" for text that isn't a recognizable text format.
+/// Sniffing alone sometimes mistakes plain text for some binary format, which would make detection unpredictable.
+fn guess_ext(bytes: &[u8]) -> String {
+    // Signatures like a shebang only match from the first byte, so look past a BOM
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let format = FileFormat::from_bytes(bytes);
+    let is_text = std::str::from_utf8(bytes).is_ok_and(|s| !s.contains('\0'));
+
+    let media_type = format.media_type();
+    let text_format = media_type.starts_with("text/")
+        || ["xml", "json", "script", "x-sh"]
+            .iter()
+            .any(|kind| media_type.contains(kind));
+
+    if is_text && !text_format {
+        "txt".to_string()
     } else {
-        // Treat input literally as UTF-8 text
-        // Can't help if it's not...
-        let bytes = s.as_bytes();
-        let ext = FileFormat::from_bytes(bytes).extension().to_string();
-
-        Ok(Input {
-            file_handler: None,
-            contents: s.into(),
-            ext,
-        })
+        format.extension().to_string()
     }
 }
 
+/// Looks an encoding up by any of the labels the WHATWG Encoding Standard gives it.
+fn parse_encoding(label: &str) -> Result<&'static encoding_rs::Encoding, SiaError> {
+    encoding_rs::Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| SiaError::InvalidConfig(format!("unknown encoding '{}'", label)))
+}
+
 pub fn parse_rgba8(s: &str) -> Result<rgb::RGBA8, String> {
     // strip leading ‘#’ if present
     let s = s.strip_prefix('#').unwrap_or(s);
@@ -211,80 +1076,2097 @@ pub fn parse_rgba8(s: &str) -> Result<rgb::RGBA8, String> {
     }
 }
 
-#[derive(Parser, Debug)]
-#[command(name = "sia", version = "0.2.0", about = "Generate a font preview")]
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Pack the default syntaxes plus every .sublime-syntax in a folder into a dump for --syntax-dump
+    BuildSyntaxDump {
+        /// Folder of .sublime-syntax files (searched recursively)
+        dir: PathBuf,
+
+        /// Where to write the dump
+        out: PathBuf,
+    },
+
+    /// Render a sample in every theme (or syntax) and tile them into one labeled contact sheet
+    Gallery {
+        /// Where to write the sheet (.svg or .png)
+        out: PathBuf,
+
+        /// Tile every syntax in the chosen --theme instead of every theme
+        #[arg(long)]
+        syntaxes: bool,
+
+        /// Most tiles to include
+        #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u32).range(1..))]
+        max: u32,
+
+        /// Tiles per row
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        per_row: u32,
+    },
+}
+
+/// Shown by `sia gallery` when no --input is given
+const GALLERY_SAMPLE: &str = "// Greets everyone\nfn main() {\n    let names = [\"sia\", \"world\"];\n    for name in names {\n        println!(\"Hello, {}!\", name);\n    }\n}\n";
+
+/// A hex color that remembers whether its alpha was spelled out (`#RRGGBBAA`) or implied (`#RRGGBB`)
+#[derive(Debug, Clone, Copy)]
+struct HexColor {
+    rgba: rgb::RGBA8,
+    explicit_alpha: bool,
+}
+
+impl FromStr for HexColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(HexColor {
+            rgba: parse_rgba8(s)?,
+            explicit_alpha: s.trim_start_matches('#').len() == 8,
+        })
+    }
+}
+
+/// A per-scope style rule such as `comment=#888888:italic`, applied over the theme
+#[derive(Debug, Clone)]
+struct StyleOverride {
+    selector: ScopeSelectors,
+    foreground: Option<Color>,
+    font_style: FontStyle,
+    // Back to the default foreground with no bold or italic, rather than adding to the theme's style
+    plain: bool,
+}
+
+impl StyleOverride {
+    /// Renders whatever `scope` selects like unhighlighted text
+    fn plain(scope: &str) -> Result<Self, SiaError> {
+        let selector = ScopeSelectors::from_str(scope.trim())
+            .map_err(|e| SiaError::InvalidConfig(format!("scope '{}': {}", scope, e)))?;
+
+        Ok(StyleOverride {
+            selector,
+            foreground: None,
+            font_style: FontStyle::empty(),
+            plain: true,
+        })
+    }
+}
+
+impl FromStr for StyleOverride {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        let (scope, style) = s.split_once('=').ok_or_else(|| {
+            SiaError::InvalidConfig(format!("style override '{}' needs scope=style", s))
+        })?;
+
+        let selector = ScopeSelectors::from_str(scope.trim())
+            .map_err(|e| SiaError::InvalidConfig(format!("scope '{}': {}", scope, e)))?;
+
+        let mut foreground = None;
+        let mut font_style = FontStyle::empty();
+        for part in style.split(':').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.to_ascii_lowercase().as_str() {
+                "bold" => font_style |= FontStyle::BOLD,
+                "italic" => font_style |= FontStyle::ITALIC,
+                "underline" => font_style |= FontStyle::UNDERLINE,
+                _ => {
+                    let c = parse_rgba8(part).map_err(SiaError::InvalidConfig)?;
+                    foreground = Some(Color {
+                        r: c.r,
+                        g: c.g,
+                        b: c.b,
+                        a: c.a,
+                    });
+                }
+            }
+        }
+
+        Ok(StyleOverride {
+            selector,
+            foreground,
+            font_style,
+            plain: false,
+        })
+    }
+}
+
+/// A language to theme pairing such as `rust=Nord`, used in place of the default theme
+#[derive(Debug, Clone)]
+struct LanguageTheme {
+    language: String,
+    theme: String,
+}
+
+impl LanguageTheme {
+    /// Matches the syntax by name or by any of its file extensions, ignoring case
+    fn matches(&self, syntax: &SyntaxReference) -> bool {
+        syntax.name.eq_ignore_ascii_case(&self.language)
+            || syntax
+                .file_extensions
+                .iter()
+                .any(|ext| ext.eq_ignore_ascii_case(&self.language))
+    }
+}
+
+impl FromStr for LanguageTheme {
+    type Err = SiaError;
+
+    fn from_str(s: &str) -> Result<Self, SiaError> {
+        match s.split_once('=') {
+            Some((language, theme)) if !language.trim().is_empty() && !theme.trim().is_empty() => {
+                Ok(LanguageTheme {
+                    language: language.trim().to_string(),
+                    theme: theme.trim().to_string(),
+                })
+            }
+            _ => Err(SiaError::InvalidConfig(format!(
+                "language theme '{}' needs language=theme",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(
+    name = "sia",
+    version = "0.2.0",
+    about = "Generate a font preview",
+    subcommand_negates_reqs = true,
+    group(ArgGroup::new("font_sources").args(["font_file", "font_dir"]).multiple(true)),
+    group(ArgGroup::new("tall_canvas").args(["size", "min_height"]).multiple(true))
+)]
 struct Cli {
-    /// Input font name (must be loaded on the system)
-    #[arg(short = 'F', long, env = "SIA_FONT")]
-    font: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input font family or PostScript name (must be loaded on the system)
+    #[arg(
+        short = 'F',
+        long,
+        env = "SIA_FONT",
+        required_unless_present_any = ["theme_preview", "font_file", "fonts"]
+    )]
+    font: Option<String>,
+
+    /// Render the input once in each of these fonts, stacked and labeled, e.g. "Fira Code,JetBrains Mono"
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with = "compare",
+        env = "SIA_FONTS"
+    )]
+    fonts: Vec<String>,
 
     /// Output (image?) file (default: output.png)
     #[arg(short = 'O', long, env = "SIA_OUT_FILE")]
     output: Option<PathBuf>,
 
+    /// Output format (default: from the output extension, otherwise png)
+    #[arg(long, value_enum, env = "SIA_FORMAT")]
+    format: Option<OutputFormat>,
+
     /// Image size WxH
     #[arg(long, env = "SIA_DIMENSIONS")]
     size: Option<Dimensions>,
 
-    /// Font size in px
-    #[arg(long, env = "SIA_FONT_SIZE")]
-    font_size: f32,
-
-    /// Background alpha
+    /// Crop the rendered image to its content, dropping blank background at the edges
+    #[arg(long, env = "SIA_TRIM")]
+    trim: bool,
+
+    /// Background to keep around the content when trimming, in px
+    #[arg(long, default_value_t = 0, env = "SIA_TRIM_PADDING")]
+    trim_padding: u32,
+
+    /// Give the SVG a viewBox so it scales cleanly to whatever size it's displayed at
+    #[arg(long, env = "SIA_VIEWBOX")]
+    viewbox: bool,
+
+    /// Fit the content into --size through the SVG's viewBox: "xMidYMid meet" letterboxes, "xMidYMid slice" crops, "none" stretches
+    #[arg(
+        long,
+        value_name = "ALIGN [meet|slice]",
+        requires = "size",
+        conflicts_with = "min_font_size",
+        env = "SIA_PRESERVE_ASPECT"
+    )]
+    preserve_aspect: Option<PreserveAspect>,
+
+    /// Rasterize at this WxH regardless of the document's logical size, fitting the content in
+    #[arg(long, env = "SIA_RASTER_SIZE")]
+    raster_size: Option<Dimensions>,
+
+    /// How semi-transparent pixels are written (default: straight for PNG, premultiplied for raw)
+    #[arg(long, value_enum, env = "SIA_ALPHA")]
+    alpha: Option<AlphaMode>,
+
+    /// DPI that pt font sizes are converted to px at. 96 matches CSS, so 12pt is 16px
+    #[arg(long, default_value_t = 96.0, env = "SIA_DPI")]
+    dpi: f32,
+
+    /// Pixel density to record in the PNG, for its printed size (default: --dpi)
+    #[arg(long, env = "SIA_OUTPUT_DPI")]
+    output_dpi: Option<f32>,
+
+    /// Empty space in pixels around the whole card, e.g. to leave room for a shadow
+    #[arg(long, default_value_t = 0.0, env = "SIA_OUTER_MARGIN")]
+    outer_margin: f32,
+
+    /// Space in pixels between the card's edges and the code, below any title bar
+    #[arg(long, default_value_t = 0.0, env = "SIA_PADDING")]
+    padding: f32,
+
+    /// Round the card's corners to this radius in pixels, leaving the corners transparent
+    #[arg(long, default_value_t = 0.0, env = "SIA_RADIUS")]
+    radius: f32,
+
+    /// Cast a drop shadow under the card, growing the image to fit it
+    #[arg(long, env = "SIA_SHADOW")]
+    shadow: bool,
+
+    /// How far the --shadow falls from the card in pixels, as XxY
+    #[arg(
+        long,
+        default_value = "0x8",
+        allow_hyphen_values = true,
+        requires = "shadow",
+        env = "SIA_SHADOW_OFFSET"
+    )]
+    shadow_offset: ShadowOffset,
+
+    /// How soft the --shadow is, as the blur's standard deviation in pixels
+    #[arg(
+        long,
+        default_value_t = 12.0,
+        requires = "shadow",
+        env = "SIA_SHADOW_BLUR"
+    )]
+    shadow_blur: f32,
+
+    /// Color of the --outer-margin area as hex (default: transparent)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_OUTER_COLOR")]
+    outer_color: Option<rgb::RGBA8>,
+
+    /// Number each line in a gutter down the left
+    #[arg(long, env = "SIA_LINE_NUMBERS")]
+    line_numbers: bool,
+
+    /// Draw a thin rule between the line numbers and the code
+    #[arg(long, requires = "line_numbers", env = "SIA_GUTTER_SEPARATOR")]
+    gutter_separator: bool,
+
+    /// Number the first line this instead of 1, e.g. to match where a snippet sits in its file
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), requires = "line_numbers", env = "SIA_START_LINE")]
+    start_line: u32,
+
+    /// The line the cursor is on, whose number is drawn brighter (1-based)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), requires = "line_numbers", env = "SIA_CURRENT_LINE")]
+    current_line: Option<u32>,
+
+    /// Draw attention to these lines, e.g. 3,7-9,12, shading them and fading the rest
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "LINES",
+        env = "SIA_HIGHLIGHT_LINES"
+    )]
+    highlight_lines: Vec<LineRange>,
+
+    /// Number lines by their distance from --current-line, like vim's relativenumber
+    #[arg(long, requires = "current_line", env = "SIA_RELATIVE_LINE_NUMBERS")]
+    relative_line_numbers: bool,
+
+    /// Draw a window title bar above the code, in the style of a platform
+    #[arg(long, value_enum, default_value_t, env = "SIA_CHROME")]
+    chrome: Chrome,
+
+    /// Shorthand for --chrome macos, a title bar with traffic lights
+    #[arg(long, conflicts_with = "chrome", env = "SIA_WINDOW")]
+    window: bool,
+
+    /// Text centered in the title bar, e.g. the file name. Adds a plain bar if there's no --chrome
+    #[arg(long, value_name = "TEXT", env = "SIA_WINDOW_TITLE")]
+    window_title: Option<String>,
+
+    /// Align each line within the width of the longest, e.g. to center ASCII art banners
+    #[arg(long, value_enum, default_value_t, env = "SIA_TEXT_ALIGN")]
+    text_align: TextAlign,
+
+    /// Space lines this many times the font's own line spacing apart, e.g. 1.2 for looser leading
+    #[arg(long, value_name = "FACTOR", env = "SIA_LINE_HEIGHT")]
+    line_height: Option<ScaleFactor>,
+
+    /// Render the first line this many times larger than the rest, e.g. 1.4 for a command above its output
+    #[arg(long, value_name = "FACTOR", env = "SIA_EMPHASIZE_FIRST_LINE")]
+    emphasize_first_line: Option<ScaleFactor>,
+
+    /// Heading drawn above the code in larger text, e.g. "Parsing the input"
+    #[arg(long, env = "SIA_TITLE")]
+    title: Option<String>,
+
+    /// Show the file's path above the code as a breadcrumb, e.g. src / parser / lexer.rs
+    #[arg(long, env = "SIA_PATH_HEADER")]
+    path_header: bool,
+
+    /// Directory the --path-header is relative to, instead of the working directory
+    #[arg(long, value_name = "DIR", requires = "path_header", env = "SIA_ROOT")]
+    root: Option<PathBuf>,
+
+    /// Where the --title sits across the image
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        requires = "title",
+        env = "SIA_TITLE_ALIGN"
+    )]
+    title_align: TextAlign,
+
+    /// Center the text vertically on a --size or --min-height canvas taller than it, e.g. for one-liners
+    #[arg(long, requires = "tall_canvas", env = "SIA_VCENTER")]
+    vcenter: bool,
+
+    /// Shade the edges where --overflow clip cuts content off, like an editor's scroll shadows
+    #[arg(long, env = "SIA_SCROLL_SHADOWS")]
+    scroll_shadows: bool,
+
+    /// What to do when the content is larger than --size or --columns
+    #[arg(long, value_enum, default_value_t = Overflow::Clip, env = "SIA_OVERFLOW")]
+    overflow: Overflow,
+
+    /// Don't let --overflow scale shrink the text below this many px
+    #[arg(long, requires = "size", env = "SIA_MIN_FONT_SIZE")]
+    min_font_size: Option<f32>,
+
+    /// What to do when the content still doesn't fit at --min-font-size
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        requires = "min_font_size",
+        env = "SIA_MIN_FONT_FALLBACK"
+    )]
+    min_font_fallback: MinFontFallback,
+
+    /// Make the canvas exactly this many columns wide, whatever the content (see --overflow)
+    #[arg(long, env = "SIA_COLUMNS")]
+    columns: Option<usize>,
+
+    /// Cut lines off at this many columns, fading out the edge like a scrolled editor
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), env = "SIA_MAX_WIDTH_CHARS")]
+    max_width_chars: Option<u16>,
+
+    /// Shorten lines longer than this many columns by eliding their middle with a …, keeping both ends
+    #[arg(long, value_name = "COLUMNS", value_parser = clap::value_parser!(u16).range(1..), conflicts_with_all = ["max_width_chars", "select", "show_whitespace", "highlight_keywords"], env = "SIA_TRUNCATE_MIDDLE")]
+    truncate_middle: Option<u16>,
+
+    /// Widen short snippets to at least this many px, text stays left-aligned
+    #[arg(long, env = "SIA_MIN_WIDTH")]
+    min_width: Option<u32>,
+
+    /// Pad short snippets to at least this many px tall, text stays top-aligned unless --vcenter
+    #[arg(long, env = "SIA_MIN_HEIGHT")]
+    min_height: Option<u32>,
+
+    /// Pad the canvas out to an aspect ratio W:H (e.g. 1.91:1), keeping the content centered
+    #[arg(long, env = "SIA_ASPECT")]
+    aspect: Option<AspectRatio>,
+
+    /// Font size, in px unless suffixed with pt (e.g. 14, 14px, 14pt)
+    #[arg(long, env = "SIA_FONT_SIZE", required_unless_present = "theme_preview")]
+    font_size: Option<FontSize>,
+
+    /// Load a font straight from a file (repeatable). --font defaults to the first one's family
+    #[arg(long, env = "SIA_FONT_FILE")]
+    font_file: Vec<PathBuf>,
+
+    /// Load every font in a directory (repeatable), so --font can name any family in it
+    #[arg(long, env = "SIA_FONT_DIR")]
+    font_dir: Vec<PathBuf>,
+
+    /// Don't load system fonts, only --font-file and --font-dir ones, so renders match across machines
+    #[arg(long, requires = "font_sources", env = "SIA_NO_SYSTEM_FONTS")]
+    no_system_fonts: bool,
+
+    /// Fail instead of warning when the font has no glyph for some of the input's characters
+    #[arg(long, env = "SIA_STRICT_COVERAGE")]
+    strict_coverage: bool,
+
+    /// Font to draw box drawing characters with when the main font lacks them
+    #[arg(long, env = "SIA_BOX_FONT")]
+    box_font: Option<String>,
+
+    /// Face to use from a font collection (.ttc), counting from 0 (default: the face matching --font)
+    #[arg(long, env = "SIA_FONT_INDEX")]
+    font_index: Option<u32>,
+
+    /// Base font weight for all text (100-900), syntax bold still goes heavier
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=1000), env = "SIA_FONT_WEIGHT")]
+    font_weight: Option<u16>,
+
+    /// Background color as hex, replacing the theme's. An alpha in #RRGGBBAA wins over --bg-alpha.
+    /// Only the background changes, unstyled text keeps the theme's foreground (see --fg-color)
+    #[arg(long, visible_alias = "bg", env = "SIA_BG_COLOR")]
+    bg_color: Option<HexColor>,
+
+    /// Color for text the theme leaves unstyled, replacing the theme's foreground. Independent of --bg-color.
+    /// An alpha in #RRGGBBAA wins over --fg-alpha
+    #[arg(long, visible_alias = "fg", env = "SIA_FG_COLOR")]
+    fg_color: Option<HexColor>,
+
+    /// Background alpha (ignored when --bg-color carries its own)
     #[arg(long, default_value_t = Alpha(1.0), env = "SIA_BG_ALPHA")]
     bg_alpha: Alpha,
 
+    /// Texture to draw over the background
+    #[arg(long, value_enum, env = "SIA_BG_PATTERN")]
+    bg_pattern: Option<BgPattern>,
+
+    /// Background pattern color as hex (default: a faint version of the theme foreground)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_BG_PATTERN_COLOR")]
+    bg_pattern_color: Option<rgb::RGBA8>,
+
+    /// Distance between background pattern repeats in px
+    #[arg(long, default_value_t = 16.0, env = "SIA_BG_PATTERN_SPACING")]
+    bg_pattern_spacing: f32,
+
     /// Text alpha
     #[arg(long, default_value_t = Alpha(1.0), env = "SIA_FG_ALPHA")]
     fg_alpha: Alpha,
 
-    /// The theme to use. Default is ocean.
-    #[arg(short = 'T', long = "theme", default_value = "base16-ocean.dark")]
-    theme: String,
+    /// Darken the edges of the card, this opaque at the corners (0-1, e.g. 0.3)
+    #[arg(long, value_name = "STRENGTH", env = "SIA_VIGNETTE")]
+    vignette: Option<Alpha>,
 
-    /// Text or file to render (\\n separated).
-    #[arg(short = 'I', long = "input", value_parser = parse_to_input)]
-    input: Input,
-}
+    /// Give the text a glow of this hex color
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_TEXT_GLOW")]
+    text_glow: Option<rgb::RGBA8>,
 
-fn main() {
-    env_logger::init();
-    if let Err(e) = run() {
-        error!("Error: {}", e);
+    /// How far the text glow spreads (blur standard deviation in px)
+    #[arg(long, default_value_t = 4.0, env = "SIA_TEXT_GLOW_BLUR")]
+    text_glow_blur: f32,
+
+    /// Shade every other line to make dense output easier to follow
+    #[arg(long, env = "SIA_ZEBRA")]
+    zebra: bool,
+
+    /// Zebra stripe color as hex (default: the theme's line highlight)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_ZEBRA_COLOR")]
+    zebra_color: Option<rgb::RGBA8>,
+
+    /// Draw a badge behind these words where they appear in comments, e.g. TODO,FIXME,HACK
+    #[arg(long, value_delimiter = ',', env = "SIA_HIGHLIGHT_KEYWORDS")]
+    highlight_keywords: Vec<String>,
+
+    /// Keyword badge color as hex (default: the theme's find highlight)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_KEYWORD_COLOR")]
+    keyword_color: Option<rgb::RGBA8>,
+
+    /// Highlight the input between these byte offsets as if selected, e.g. 120..164
+    #[arg(long, value_name = "START..END", conflicts_with_all = ["encoding", "strip_ansi", "control_chars", "focus_match", "prose", "normalize_indent", "wrap", "max_width", "strip_trailing_whitespace", "lines", "paginate"], env = "SIA_SELECT")]
+    select: Option<ByteRange>,
+
+    /// Selection color as hex (default: the theme's selection)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_SELECTION_COLOR")]
+    selection_color: Option<rgb::RGBA8>,
+
+    /// The theme to use, base16-ocean.dark unless a --language-theme matches. "auto" follows the system appearance.
+    #[arg(short = 'T', long = "theme")]
+    theme: Option<String>,
+
+    /// A .tmTheme file to highlight with, or a directory of them to choose from by name with --theme
+    #[arg(long, value_name = "PATH", env = "SIA_THEME_FILE")]
+    theme_file: Option<PathBuf>,
+
+    /// Write a note in the margin beside a line, e.g. 12="needs refactor" (repeatable)
+    #[arg(long, value_name = "LINE=NOTE", env = "SIA_ANNOTATE")]
+    annotate: Vec<Annotation>,
+
+    /// Annotation color as hex (default: the theme foreground, dimmed)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_ANNOTATION_COLOR")]
+    annotation_color: Option<rgb::RGBA8>,
+
+    /// Theme to use for a language unless --theme is given, e.g. "rust=Nord,bash=Dracula"
+    #[arg(long, value_delimiter = ',', env = "SIA_LANGUAGE_THEME")]
+    language_theme: Vec<LanguageTheme>,
+
+    /// Syntax to highlight with regardless of detection, by extension or name, e.g. "rs" or "Rust"
+    #[arg(long, value_name = "EXT", conflicts_with = "concat", env = "SIA_LANG")]
+    lang: Option<String>,
+
+    /// Syntax to highlight with when the language can't be detected, by name, e.g. "Log" or "INI"
+    #[arg(long, value_name = "NAME", env = "SIA_FALLBACK_LANGUAGE")]
+    fallback_language: Option<String>,
+
+    /// Lighten or darken token colors until they reach this WCAG contrast ratio against the background, e.g. 4.5
+    #[arg(long, env = "SIA_MIN_CONTRAST")]
+    min_contrast: Option<f32>,
+
+    /// Push every token color this many times further from the background, e.g. 1.3 for punchier colors
+    #[arg(long, value_name = "FACTOR", env = "SIA_CONTRAST_BOOST")]
+    contrast_boost: Option<f32>,
+
+    /// Per-scope style overrides, e.g. "comment=#888888:italic,string=#00ff00"
+    #[arg(long, value_delimiter = ',', env = "SIA_STYLE_OVERRIDE")]
+    style_override: Vec<StyleOverride>,
+
+    /// Render these scopes in the default foreground, unstyled, e.g. "comment,punctuation"
+    #[arg(long, value_delimiter = ',', value_name = "SCOPES", value_parser = StyleOverride::plain, env = "SIA_PLAIN_SCOPES")]
+    plain_scopes: Vec<StyleOverride>,
+
+    /// Render comments in the default foreground instead of the theme's muted comment color
+    #[arg(long, env = "SIA_NO_DIM_COMMENTS")]
+    no_dim_comments: bool,
+
+    /// Flip the theme's lightness, giving a light variant of a dark theme and vice versa
+    #[arg(long, env = "SIA_INVERT")]
+    invert: bool,
+
+    /// Load extra themes from a serialized syntect ThemeSet, such as bat's themes.bin
+    #[arg(long, env = "SIA_THEME_CACHE")]
+    theme_cache: Option<PathBuf>,
+
+    /// Highlight lines without their line endings, for themes that color past the end of a line
+    #[arg(long, conflicts_with = "syntax_dump", env = "SIA_NO_TRAILING_STYLE")]
+    no_trailing_style: bool,
+
+    /// Keep fast-loading copies of the syntaxes and themes here, refreshed when their sources change
+    #[arg(long, env = "SIA_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Load syntaxes from a dump made by `sia build-syntax-dump` instead of the defaults
+    #[arg(long, env = "SIA_SYNTAX_DUMP")]
+    syntax_dump: Option<PathBuf>,
+
+    /// Also load every .sublime-syntax file under this directory, alongside the defaults. Can be repeated
+    #[arg(long, value_name = "DIR", env = "SIA_SYNTAX_DIR")]
+    syntax_dir: Vec<PathBuf>,
+
+    /// Print the colors a theme gives common scopes (keyword, string, comment...) and exit
+    #[arg(long, value_name = "THEME")]
+    theme_preview: Option<String>,
+
+    /// Theme picked by `--theme auto` on a light system
+    #[arg(long, default_value = "base16-ocean.light", env = "SIA_LIGHT_THEME")]
+    light_theme: String,
+
+    /// Theme picked by `--theme auto` on a dark system
+    #[arg(long, default_value = "base16-ocean.dark", env = "SIA_DARK_THEME")]
+    dark_theme: String,
+
+    /// Text or file to render (\\n separated), or - to read from stdin.
+    /// Repeat it to render several inputs at once, each to its own image in the --output directory
+    #[arg(short = 'I', long = "input", value_parser = parse_to_input, required_unless_present_any = ["from_clipboard", "git_diff", "compare", "concat", "theme_preview"])]
+    input: Vec<Input>,
+
+    /// Render whatever text is on the system clipboard instead of --input
+    #[arg(long, conflicts_with = "input")]
+    from_clipboard: bool,
+
+    /// Render this file with the lines `git diff` reports as added highlighted and the rest dimmed
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input", "from_clipboard", "focus_match", "wrap", "prose"])]
+    git_diff: Option<PathBuf>,
+
+    /// Read the --git-diff line changes from this patch instead of running git
+    #[arg(long, requires = "git_diff")]
+    patch: Option<PathBuf>,
+
+    /// Render only these line ranges, e.g. 10-12,40-42, marking the lines skipped between them
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["git_diff", "compare", "focus_match", "wrap", "prose"], env = "SIA_LINES")]
+    lines: Vec<LineRange>,
+
+    /// Split the render into images of this many lines each, numbered output-1, output-2...
+    #[arg(long, value_name = "LINES", value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["lines", "git_diff", "compare", "concat", "fonts", "focus_match", "wrap", "prose"], env = "SIA_PAGINATE")]
+    paginate: Option<u32>,
+
+    /// Render two versions of a file next to each other, tinting the lines removed and added between them
+    #[arg(long, num_args = 2, value_names = ["BEFORE", "AFTER"], conflicts_with_all = ["input", "from_clipboard", "git_diff", "focus_match", "wrap", "prose"])]
+    compare: Vec<PathBuf>,
+
+    /// Whether --compare puts the two versions side by side or one above the other
+    #[arg(long, value_enum, default_value_t, env = "SIA_COMPARE_LAYOUT")]
+    compare_layout: CompareLayout,
+
+    /// Render several files one after another in one image, each under its name
+    #[arg(long, num_args = 1.., value_name = "FILES", conflicts_with_all = ["input", "from_clipboard", "git_diff", "compare", "fonts", "lines"])]
+    concat: Vec<PathBuf>,
+
+    /// Removed line color for --compare as hex (default: a translucent red)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_DIFF_REMOVED_COLOR")]
+    diff_removed_color: Option<rgb::RGBA8>,
+
+    /// Added line color for --git-diff as hex (default: a translucent green)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_DIFF_ADDED_COLOR")]
+    diff_added_color: Option<rgb::RGBA8>,
+
+    /// Draw a vertical ruler at the given column(s), e.g. 80 or 80,100
+    #[arg(long, value_delimiter = ',', env = "SIA_RULER")]
+    ruler: Vec<usize>,
+
+    /// Ruler color as hex (default: the theme's guide color)
+    #[arg(long, value_parser = parse_rgba8, env = "SIA_RULER_COLOR")]
+    ruler_color: Option<rgb::RGBA8>,
+
+    /// Decode the input file from this encoding instead of UTF-8, any WHATWG label (e.g. shift_jis, latin1, utf-16le)
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding, env = "SIA_ENCODING")]
+    encoding: Option<&'static encoding_rs::Encoding>,
+
+    /// Only render the first line matching this regex, plus --focus-context lines around it
+    #[arg(long, env = "SIA_FOCUS_MATCH")]
+    focus_match: Option<Regex>,
+
+    /// Lines of context to keep either side of the --focus-match line
+    #[arg(long, default_value_t = 3, env = "SIA_FOCUS_CONTEXT")]
+    focus_context: usize,
+
+    /// Skip highlighting and emit each line verbatim, for logs and ASCII art
+    #[arg(long, env = "SIA_RAW")]
+    raw: bool,
+
+    /// Wrap lines longer than this many columns, breaking at spaces where possible
+    #[arg(long, value_name = "COLUMNS", env = "SIA_WRAP")]
+    wrap: Option<usize>,
+
+    /// Wrap lines wider than this many pixels, like --wrap but measured in the font
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "wrap", env = "SIA_MAX_WIDTH")]
+    max_width: Option<u32>,
+
+    /// Treat the input as prose: join single newlines into paragraphs (pair with --wrap)
+    #[arg(long, env = "SIA_PROSE")]
+    prose: bool,
+
+    /// Trim trailing spaces and tabs from each line before rendering
+    #[arg(long, env = "SIA_STRIP_TRAILING_WHITESPACE")]
+    strip_trailing_whitespace: bool,
+
+    /// Rewrite all indentation as spaces or tabs, with stops every --tab-width columns
+    #[arg(long, value_enum, env = "SIA_NORMALIZE_INDENT")]
+    normalize_indent: Option<IndentStyle>,
+
+    /// Expand tabs to the next multiple of this many columns, counted from the start of the line
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..), env = "SIA_TAB_WIDTH")]
+    tab_width: u16,
+
+    /// Mark spaces with a dot and tabs with an arrow, in the indentation only or everywhere
+    #[arg(long, value_enum, env = "SIA_SHOW_WHITESPACE")]
+    show_whitespace: Option<ShowWhitespace>,
+
+    /// How spaces survive in the SVG: xml:space="preserve", or no-break spaces for renderers that mishandle it
+    #[arg(long, value_enum, default_value_t, env = "SIA_SPACE_MODE")]
+    space_mode: SpaceMode,
+
+    /// Mark each tab stop a tab reaches with a faint tick, to tell tabs from spaces
+    #[arg(long, env = "SIA_TAB_STOPS")]
+    tab_stops: bool,
+
+    /// Mark the end of each line with a faint ⏎ (or ↵ for CRLF), to spot mixed line endings
+    #[arg(long, env = "SIA_SHOW_LINE_ENDINGS")]
+    show_line_endings: bool,
+
+    /// Render the empty row after a trailing newline, which is otherwise dropped
+    #[arg(long, env = "SIA_PRESERVE_EMPTY_LAST_LINE")]
+    preserve_empty_last_line: bool,
+
+    /// Record the theme, font, language and time of the render in the PNG's text chunks
+    #[arg(long, env = "SIA_EMBED_METADATA")]
+    embed_metadata: bool,
+
+    /// Antialias text when rasterizing; turn off for crisp pixel fonts and tiny sizes
+    #[arg(long, value_enum, default_value_t = Antialias::On, env = "SIA_TEXT_AA")]
+    text_aa: Antialias,
+
+    /// Draw the render onto this PNG instead of a blank canvas, saving the result to --output
+    #[arg(
+        long,
+        value_name = "BASE",
+        conflicts_with = "output_sizes",
+        env = "SIA_ONTO"
+    )]
+    onto: Option<PathBuf>,
+
+    /// Where the top left of the render lands on the --onto image, e.g. 40,40
+    #[arg(long, default_value = "0,0", requires = "onto", env = "SIA_AT")]
+    at: Offset,
+
+    /// Also write name-preview.png, the render over a checkerboard so transparent areas stand out
+    #[arg(long, env = "SIA_CHECKERBOARD_PREVIEW")]
+    checkerboard_preview: bool,
+
+    /// Render at several scales at once (e.g. 1x,2x,3x), writing name@2x.png and so on
+    #[arg(long, value_delimiter = ',', env = "SIA_OUTPUT_SIZES")]
+    output_sizes: Vec<ScaleFactor>,
+
+    /// Print a summary (lines, language, theme, font, size) to stderr after rendering
+    #[arg(long, env = "SIA_STATS")]
+    stats: bool,
+
+    /// Overlay each line's baseline and the canvas bounds, for checking the layout math
+    #[arg(long, hide = true)]
+    debug_grid: bool,
+
+    /// Overlay baselines, the text's bounding box and the image size, for sizing it within a layout
+    #[arg(long, env = "SIA_GUIDES")]
+    guides: bool,
+
+    /// Don't show progress while highlighting large inputs
+    #[arg(short = 'q', long, env = "SIA_QUIET")]
+    quiet: bool,
+
+    /// Hide warnings from font loading and text shaping, keeping sia's own
+    #[arg(long, env = "SIA_QUIET_FONTS")]
+    quiet_fonts: bool,
+
+    /// Give up on rendering after this many seconds (default: no limit)
+    #[arg(long, env = "SIA_TIMEOUT")]
+    timeout: Option<f64>,
+
+    /// Remove ANSI color codes from the input before highlighting
+    #[arg(long, env = "SIA_STRIP_ANSI")]
+    strip_ansi: bool,
+
+    /// Strip control characters like form feeds, or show them as their Unicode control pictures
+    #[arg(long, value_enum, default_value_t, env = "SIA_CONTROL_CHARS")]
+    control_chars: ControlChars,
+
+    /// Convert text to vector paths when writing SVG (portable, but no longer selectable)
+    #[arg(long, env = "SIA_OUTLINE_TEXT")]
+    outline_text: bool,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    // Font loading and shaping complain about every broken or unmatched font on the system,
+    // so those are held to errors while sia's own warnings still follow RUST_LOG
+    let mut logger = env_logger::Builder::from_default_env();
+    if cli.quiet_fonts {
+        for module in ["fontdb", "usvg::text"] {
+            logger.filter_module(module, log::LevelFilter::Error);
+        }
+    }
+    logger.init();
+
+    if let Err(e) = run(cli) {
+        error!("Error: {}", e);
         std::process::exit(1);
     }
 }
 
-fn run() -> Result<(), Error> {
-    let cli = Cli::parse();
+fn run(cli: Cli) -> Result<(), Error> {
+    if let Some(Command::BuildSyntaxDump { dir, out }) = &cli.command {
+        return build_syntax_dump(dir, out);
+    }
+
+    if let Some(Command::Gallery {
+        out,
+        syntaxes,
+        max,
+        per_row,
+    }) = &cli.command
+    {
+        let ctx = RenderContext::new(&cli)?;
+        return render_gallery(&cli, &ctx, out, *syntaxes, *max as usize, *per_row as usize);
+    }
+
+    if let Some(name) = &cli.theme_preview {
+        return print_theme_preview(name, cli.theme_cache.as_deref());
+    }
+
+    // Several inputs share the one context, so fonts, syntaxes and themes only load once
+    let render = |cli: Cli, ctx: &RenderContext| match cli.input.len() {
+        0 | 1 => render(cli, ctx),
+        _ => render_batch(cli, ctx),
+    };
+
+    let Some(seconds) = cli.timeout else {
+        let ctx = RenderContext::new(&cli)?;
+        return render(cli, &ctx);
+    };
+
+    // The worker can't be interrupted, but returning lets main exit the process out from under it
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(RenderContext::new(&cli).and_then(|ctx| render(cli, &ctx)));
+    });
+
+    match rx.recv_timeout(Duration::from_secs_f64(seconds)) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(SiaError::Message(format!(
+            "render took longer than {}s, giving up",
+            seconds
+        ))
+        .into()),
+        Err(RecvTimeoutError::Disconnected) => {
+            Err(SiaError::Message("render thread panicked".into()).into())
+        }
+    }
+}
+
+/// Everything that's slow to load but doesn't depend on the input, built once and shared by renders
+struct RenderContext {
+    fontdb: Arc<Database>,
+    // The first --font-file's family, for when --font is left out
+    file_family: Option<String>,
+    syntaxes: SyntaxSet,
+    themes: Themes,
+}
+
+impl RenderContext {
+    fn new(cli: &Cli) -> Result<Self, Error> {
+        let mut fontdb = Database::new();
+
+        // Skipping system fonts keeps renders identical across machines
+        if !cli.no_system_fonts {
+            fontdb.load_system_fonts();
+        }
+
+        // Vendored font folders, which --font then picks a family out of
+        for path in &cli.font_dir {
+            if !path.is_dir() {
+                return Err(
+                    SiaError::FontLoad(format!("{} isn't a directory", path.display())).into(),
+                );
+            }
+            fontdb.load_fonts_dir(path);
+        }
+
+        // Explicit font files, remembering the first family in case --font was left out
+        let mut file_family = None;
+        for path in &cli.font_file {
+            let ids = fontdb.load_font_source(Source::File(path.clone()));
+            let Some(&id) = ids.first() else {
+                return Err(
+                    SiaError::FontLoad(format!("no fonts found in {}", path.display())).into(),
+                );
+            };
+
+            if file_family.is_none() {
+                file_family = fontdb
+                    .face(id)
+                    .and_then(|face| face.families.first())
+                    .map(|family| family.0.clone());
+            }
+        }
+
+        // A prebuilt dump is far quicker to load than syntax definitions, and may carry custom ones
+        let cache_dir = cli.cache_dir.as_deref();
+        let syntaxes = match &cli.syntax_dump {
+            Some(path) => load_cached(cache_dir, &cache_name("syntaxes", path), &[path], || {
+                from_dump_file(path)
+                    .or_else(|_| from_uncompressed_dump_file(path))
+                    .map_err(|e| {
+                        SiaError::Parse(format!(
+                            "couldn't read syntax dump {} ({})",
+                            path.display(),
+                            e
+                        ))
+                    })
+            })?,
+            None if cli.no_trailing_style => {
+                load_cached(cache_dir, "syntaxes-nonewlines.dump", &[], || {
+                    Ok(SyntaxSet::load_defaults_nonewlines())
+                })?
+            }
+            None => load_cached(cache_dir, "syntaxes-newlines.dump", &[], || {
+                Ok(SyntaxSet::load_defaults_newlines())
+            })?,
+        };
+
+        // Extra definitions are merged in once here, rather than for every input that's rendered
+        let syntaxes = if cli.syntax_dir.is_empty() {
+            syntaxes
+        } else {
+            let mut builder = syntaxes.into_builder();
+            for dir in &cli.syntax_dir {
+                if !dir.is_dir() {
+                    return Err(
+                        SiaError::Parse(format!("{} isn't a directory", dir.display())).into(),
+                    );
+                }
+                builder
+                    .add_from_folder(dir, !cli.no_trailing_style)
+                    .map_err(|e| {
+                        SiaError::Parse(format!(
+                            "couldn't load syntaxes from {} ({})",
+                            dir.display(),
+                            e
+                        ))
+                    })?;
+            }
+            builder.build()
+        };
+
+        // Bundled themes, plus the user's own from --theme-cache and --theme-file
+        let themes = Themes::load(
+            cli.theme_cache.as_deref(),
+            cache_dir,
+            cli.theme_file.as_deref(),
+        )?;
+
+        Ok(RenderContext {
+            fontdb: Arc::new(fontdb),
+            file_family,
+            syntaxes,
+            themes,
+        })
+    }
+}
+
+/// Whether two faces were loaded from the same file or buffer.
+fn same_source(a: &Source, b: &Source) -> bool {
+    match (a, b) {
+        (Source::File(a), Source::File(b)) => a == b,
+        (Source::SharedFile(a, _), Source::SharedFile(b, _)) => a == b,
+        (Source::Binary(a), Source::Binary(b)) => Arc::ptr_eq(a, b),
+        _ => false,
+    }
+}
+
+/// Loads from an uncompressed dump called `name` in `dir`, so long as it's newer than sia itself and
+/// every file in `sources`. Otherwise (or without a `dir`) falls back to `load`, refreshing the dump.
+fn load_cached<T, F>(
+    dir: Option<&Path>,
+    name: &str,
+    sources: &[&Path],
+    load: F,
+) -> Result<T, SiaError>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Result<T, SiaError>,
+{
+    let Some(dir) = dir else {
+        return load();
+    };
+
+    let path = dir.join(name);
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    let exe = std::env::current_exe().ok();
+    let fresh = modified(&path).is_some_and(|cached| {
+        exe.as_deref()
+            .into_iter()
+            .chain(sources.iter().copied())
+            .all(|source| modified(source).is_some_and(|m| m <= cached))
+    });
+
+    if fresh {
+        match from_uncompressed_dump_file(&path) {
+            Ok(value) => return Ok(value),
+            Err(e) => warn!("ignoring unreadable cache {} ({})", path.display(), e),
+        }
+    }
+
+    let value = load()?;
+
+    // A cache that can't be written only costs speed, so don't fail the render over it
+    if let Err(e) = fs::create_dir_all(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|_| dump_to_uncompressed_file(&value, &path).map_err(|e| e.to_string()))
+    {
+        warn!("couldn't write cache {} ({})", path.display(), e);
+    }
+
+    Ok(value)
+}
+
+/// Cache file name for a dump loaded from `source`, unique per path
+fn cache_name(kind: &str, source: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    format!("{}-{:016x}.dump", kind, hasher.finish())
+}
+
+/// The bundled themes plus any loaded from a theme cache, which take precedence
+struct Themes {
+    bundled: LazyThemeSet,
+    cached: ThemeSet,
+}
+
+impl Themes {
+    fn load(
+        cache: Option<&Path>,
+        cache_dir: Option<&Path>,
+        theme_file: Option<&Path>,
+    ) -> Result<Self, SiaError> {
+        let mut cached = match cache {
+            // bat writes its cache compressed, but plain syntect dumps are common too
+            Some(path) => load_cached(cache_dir, &cache_name("themes", path), &[path], || {
+                from_dump_file(path)
+                    .or_else(|_| from_uncompressed_dump_file(path))
+                    .map_err(|e| {
+                        SiaError::Parse(format!(
+                            "couldn't read theme cache {} ({}). It may have been built by an incompatible bat/syntect version",
+                            path.display(),
+                            e
+                        ))
+                    })
+            })?,
+            None => ThemeSet::new(),
+        };
+
+        // Named after their files like syntect's own folder loading, and ahead of any cached theme of the same name
+        if let Some(path) = theme_file {
+            let loaded = if path.is_dir() {
+                ThemeSet::load_from_folder(path).map(|set| set.themes)
+            } else {
+                ThemeSet::get_theme(path).map(|theme| [(theme_file_name(path), theme)].into())
+            };
+            let loaded = loaded.map_err(|e| {
+                SiaError::InvalidConfig(format!("couldn't load {} ({})", path.display(), e))
+            })?;
+            cached.themes.extend(loaded);
+        }
+
+        // Drawing leans on a default text color, which a user's theme may leave out. Black or
+        // white, whichever reads on its background, stands in
+        for theme in cached.themes.values_mut() {
+            if theme.settings.foreground.is_none() {
+                let dark = theme
+                    .settings
+                    .background
+                    .is_some_and(|bg| relative_luminance(bg) < 0.5);
+                theme.settings.foreground = Some(if dark { Color::WHITE } else { Color::BLACK });
+            }
+        }
+
+        Ok(Themes {
+            bundled: LazyThemeSet::from(extra()),
+            cached,
+        })
+    }
+
+    /// Every theme's name, the user's own first
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.cached.themes.keys().cloned().collect();
+        names.extend(self.bundled.theme_names().map(String::from));
+        names
+    }
+
+    fn get(&self, name: &str) -> Option<&Theme> {
+        self.cached
+            .themes
+            .get(name)
+            .or_else(|| self.bundled.get(name))
+    }
+}
+
+/// The name a single --theme-file is known by, its file name without the extension
+fn theme_file_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn resolve_theme<'a>(themes: &'a Themes, name: &str) -> Result<&'a Theme, SiaError> {
+    // People often leave off the variant suffix, so try the dark then light flavor before giving up
+    match themes.get(name) {
+        Some(theme) => Ok(theme),
+        None => ["dark", "light"]
+            .iter()
+            .find_map(|variant| {
+                let candidate = format!("{}.{}", name, variant);
+                let theme = themes.get(&candidate)?;
+                info!("No theme named '{}', using '{}'", name, candidate);
+                Some(theme)
+            })
+            .ok_or_else(|| SiaError::InvalidConfig(format!("unknown theme '{}'", name))),
+    }
+}
+
+/// Builds the default syntaxes plus the ones in `dir` into a dump at `out`
+fn build_syntax_dump(dir: &Path, out: &Path) -> Result<(), Error> {
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    builder.add_from_folder(dir, true).map_err(|e| {
+        SiaError::Parse(format!(
+            "couldn't load syntaxes from {} ({})",
+            dir.display(),
+            e
+        ))
+    })?;
+
+    let syntaxes = builder.build();
+    dump_to_file(&syntaxes, out)
+        .map_err(|e| SiaError::Message(format!("couldn't write {} ({})", out.display(), e)))?;
+
+    info!(
+        "Wrote {} syntaxes to {}",
+        syntaxes.syntaxes().len(),
+        out.display()
+    );
+    Ok(())
+}
+
+/// Prints the color (and style) a theme gives a handful of common scopes
+fn print_theme_preview(name: &str, cache: Option<&Path>) -> Result<(), Error> {
+    let available_themes = Themes::load(cache, None, None)?;
+    let theme = resolve_theme(&available_themes, name)?;
+    let highlighter = Highlighter::new(theme);
+
+    let scopes = [
+        "comment",
+        "keyword",
+        "storage.type",
+        "string",
+        "constant.numeric",
+        "constant.language",
+        "variable",
+        "variable.parameter",
+        "entity.name.function",
+        "entity.name.type",
+        "support.function",
+        "punctuation",
+        "invalid",
+    ];
+
+    let hex = |c: Option<Color>| match c {
+        Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+        None => "-".into(),
+    };
+
+    println!("{:<24} {}", "background", hex(theme.settings.background));
+    println!("{:<24} {}", "foreground", hex(theme.settings.foreground));
+
+    for scope in scopes {
+        let scope = Scope::new(scope).map_err(|e| SiaError::Parse(e.to_string()))?;
+        let style = highlighter.style_for_stack(&[scope]);
+
+        let mut modifiers = Vec::new();
+        if style.font_style.contains(FontStyle::BOLD) {
+            modifiers.push("bold");
+        }
+        if style.font_style.contains(FontStyle::ITALIC) {
+            modifiers.push("italic");
+        }
+        if style.font_style.contains(FontStyle::UNDERLINE) {
+            modifiers.push("underline");
+        }
+
+        let row = format!(
+            "{:<24} {} {}",
+            scope.build_string(),
+            hex(Some(style.foreground)),
+            modifiers.join(" ")
+        );
+        println!("{}", row.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Renders each of several --input to its own image, named after the input, and carries on past
+/// any that fail so one bad file doesn't cost the rest.
+fn render_batch(cli: Cli, ctx: &RenderContext) -> Result<(), Error> {
+    let dir = match &cli.output {
+        Some(path) if path.is_dir() || path.to_string_lossy().ends_with('/') => {
+            fs::create_dir_all(path)?;
+            path.clone()
+        }
+        Some(path) => {
+            return Err(SiaError::InvalidConfig(format!(
+                "with several --input, --output needs to be a directory, not {}",
+                path.display()
+            ))
+            .into())
+        }
+        None => PathBuf::from("."),
+    };
+    let ext = cli
+        .format
+        .unwrap_or(OutputFormat::Png)
+        .to_possible_value()
+        .unwrap()
+        .get_name()
+        .to_string();
+
+    let total = cli.input.len();
+    let mut failed = 0;
+    let mut written = HashSet::new();
+    for (i, input) in cli.input.iter().enumerate() {
+        // Literal and piped text has no file name to borrow, so it goes by its position
+        let name = match &input.file_handler {
+            Some(path) => path.display().to_string(),
+            None => format!("input {}", i + 1),
+        };
+        let stem = match input
+            .file_handler
+            .as_ref()
+            .and_then(|path| path.file_stem())
+        {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => format!("input-{}", i + 1),
+        };
+        // Files from different directories can share a name, later ones get numbered rather than overwrite it
+        let file_name = (1..)
+            .map(|n| match n {
+                1 => format!("{}.{}", stem, ext),
+                _ => format!("{}-{}.{}", stem, n, ext),
+            })
+            .find(|file_name| !written.contains(file_name))
+            .expect("some suffix is always free");
+        written.insert(file_name.clone());
+
+        let result = render(
+            Cli {
+                input: vec![input.clone()],
+                output: Some(dir.join(file_name)),
+                ..cli.clone()
+            },
+            ctx,
+        );
+        if let Err(e) = result {
+            error!("{}: {}", name, e);
+            failed += 1;
+        }
+    }
+
+    // Printed like --stats rather than logged, since it's the answer to the run and info! is hidden by default
+    if !cli.quiet {
+        eprintln!("Rendered {} of {} inputs", total - failed, total);
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(SiaError::Message(format!("{} of {} inputs failed", failed, total)).into()),
+    }
+}
+
+fn render(cli: Cli, ctx: &RenderContext) -> Result<(), Error> {
+    // clap guarantees one of the two is present
+    let mut input = match (cli.input.first(), &cli.git_diff, cli.compare.as_slice()) {
+        // The rest of --concat is read when the image is put together
+        _ if !cli.concat.is_empty() => parse_to_input(&cli.concat[0].to_string_lossy())?,
+        (_, _, [_, after]) => parse_to_input(&after.to_string_lossy())?,
+        (Some(input), _, _) => input.clone(),
+        (None, Some(path), _) => parse_to_input(&path.to_string_lossy())?,
+        (None, None, _) => literal_input(&read_clipboard()?),
+    };
 
-    // Get the font database early to get available fonts
-    let mut tree_options = usvg::Options::default();
-    tree_options.fontdb_mut().load_system_fonts(); // System fonts should always be loaded? Maybe this is needless
+    // Checked against the whole input, which pages and excerpts then number their lines from
+    let last_line = cli.start_line as usize - 1 + input.contents.lines().count();
+    if let Some(line) = cli
+        .highlight_lines
+        .iter()
+        .flat_map(|range| [range.start, range.end])
+        .find(|&line| line < cli.start_line as usize || line > last_line)
+    {
+        return Err(SiaError::InvalidConfig(format!(
+            "--highlight-lines {} is outside the input's lines {}-{}",
+            line, cli.start_line, last_line
+        ))
+        .into());
+    }
+
+    if let Some(per_page) = cli.paginate {
+        return paginate(cli, ctx, input, per_page as usize);
+    }
+
+    // Compared against the input, which holds the after side
+    let mut before = match cli.compare.as_slice() {
+        [before, _] => Some(parse_to_input(&before.to_string_lossy())?),
+        _ => None,
+    };
+
+    // Classified against the file as it is on disk, so nothing reshaping lines may run before this
+    let (diff_added, diff_removed) = match (&cli.git_diff, &cli.patch, cli.compare.as_slice()) {
+        (_, _, [before, after]) => {
+            let patch = git_diff_files(before, after)?;
+            (Some(added_lines(&patch)), Some(removed_lines(&patch)))
+        }
+        (Some(_), Some(patch), _) => (Some(added_lines(&fs::read_to_string(patch)?)), None),
+        (Some(path), None, _) => (Some(added_lines(&git_diff(path)?)), None),
+        (None, _, _) => (None, None),
+    };
+
+    prepare_input(&cli, &mut input)?;
+    force_language(&cli, &ctx.syntaxes, &mut input)?;
+    fall_back_language(&cli, &ctx.syntaxes, &mut input);
+
+    if cli.path_header && input.file_handler.is_none() {
+        info!("--path-header needs a file to take the path from, leaving it out");
+    }
+
+    // Cut down to the requested lines, remembering where each came from for the line numbers
+    let line_origins = if cli.lines.is_empty() {
+        None
+    } else {
+        let (excerpt, origins) = select_lines(&input.contents, &cli.lines)?;
+        input.contents = excerpt;
+        Some(origins)
+    };
+    if let Some(before) = before.as_mut() {
+        prepare_input(&cli, before)?;
+        force_language(&cli, &ctx.syntaxes, before)?;
+        fall_back_language(&cli, &ctx.syntaxes, before);
+    }
+
+    // Minified files can be one enormous line, far too wide to lay out or rasterize as is
+    let fixed_width = cli.columns.is_some()
+        || cli.max_width_chars.is_some()
+        || cli.truncate_middle.is_some()
+        || cli.max_width.is_some();
+    if let (false, Some(n)) = (
+        fixed_width,
+        input
+            .contents
+            .lines()
+            .position(|line| line.chars().count() > LONG_LINE_LIMIT),
+    ) {
+        return Err(SiaError::InvalidConfig(format!(
+            "line {} is over {} characters, pass --wrap to break it up or --columns/--max-width-chars to cut it off",
+            n + 1,
+            LONG_LINE_LIMIT
+        ))
+        .into());
+    }
+
+    // Shares the context's fonts rather than copying them
+    let mut tree_options = usvg::Options {
+        fontdb: Arc::clone(&ctx.fontdb),
+        ..Default::default()
+    };
 
     // Determine the output file
+    // An explicit --format wins, otherwise go by the output extension
+    let format = match (cli.format, &cli.output) {
+        (Some(format), _) => format,
+        (None, Some(path)) => OutputFormat::from_extension(path)?.unwrap_or(OutputFormat::Png),
+        (None, None) => OutputFormat::Png,
+    };
+    // HTML holds one highlighted block, not the side-by-side panels these put together,
+    // and it has no gutter, shading or callouts, so refuse rather than quietly drop them
+    if let OutputFormat::Html = format {
+        let unsupported = [
+            ("--compare", !cli.compare.is_empty()),
+            ("--concat", !cli.concat.is_empty()),
+            ("--fonts", !cli.fonts.is_empty()),
+            ("--line-numbers", cli.line_numbers),
+            ("--highlight-lines", !cli.highlight_lines.is_empty()),
+            ("--fg-alpha", cli.fg_alpha.0 < 1.0),
+            ("--annotate", !cli.annotate.is_empty()),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(
+                SiaError::InvalidConfig(format!("{} can't be written as HTML", flag)).into(),
+            );
+        }
+    }
+
+    let output = cli.output.clone().unwrap_or_else(|| {
+        PathBuf::from("output").with_extension(format.to_possible_value().unwrap().get_name())
+    });
+
+    let syntaxes = &ctx.syntaxes;
+
+    // An explicit --theme wins, then a mapping for the detected language, then the default
+    let syntax = syntaxes
+        .find_syntax_by_token(&input.ext)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    // A single --theme-file is the theme, while a directory of them is only chosen from
+    let single_file = cli
+        .theme_file
+        .as_deref()
+        .filter(|path| !path.is_dir())
+        .map(theme_file_name);
+    let requested = match (&cli.theme, &single_file) {
+        (Some(_), Some(_)) => {
+            return Err(SiaError::InvalidConfig(
+                "--theme-file names a single theme, leave out --theme or pass a directory".into(),
+            )
+            .into())
+        }
+        (_, Some(name)) => name,
+        (Some(theme), None) => theme,
+        (None, None) => cli
+            .language_theme
+            .iter()
+            .find(|mapping| mapping.matches(syntax))
+            .map_or("base16-ocean.dark", |mapping| &mapping.theme),
+    };
+
+    // Resolve "auto" to the member of the light/dark pair matching the system
+    let theme_name = if requested.eq_ignore_ascii_case("auto") {
+        if prefers_dark() {
+            cli.dark_theme.as_str()
+        } else {
+            cli.light_theme.as_str()
+        }
+    } else {
+        requested
+    };
+
+    let theme = resolve_theme(&ctx.themes, theme_name)?;
+
+    let inverted;
+    let theme = if cli.invert {
+        inverted = invert_theme(theme);
+        &inverted
+    } else {
+        theme
+    };
+
+    // clap only lets these be missing for --theme-preview (which never gets here) or when a font file names it
+    let font_name = &cli
+        .font
+        .clone()
+        .or_else(|| ctx.file_family.clone())
+        .or_else(|| cli.fonts.first().cloned())
+        .expect("--font is required");
+    let font_name = &resolve_family(&cli, &ctx.fontdb, font_name)?;
+
+    // Setup the rendering
+    // Only physical units like pt depend on this, px lengths (and so the image size) never do
+    tree_options.dpi = cli.dpi;
+    tree_options.font_family = font_name.clone();
+
+    // Only text is affected, shapes like backgrounds and badges keep their smooth edges
+    if let Antialias::Off = cli.text_aa {
+        tree_options.text_rendering = usvg::TextRendering::OptimizeSpeed;
+    }
+
+    // Points depend on the DPI, so only resolve once it's settled
+    let font_size = cli
+        .font_size
+        .expect("--font-size is required")
+        .to_px(tree_options.dpi);
+    tree_options.font_size = font_size;
+
+    let (font, font_bytes) = load_font(&cli, &ctx.fontdb, font_name)?;
+
+    // Columns only line up when every glyph shares the same advance
+    if !is_monospace(&font, font_size) {
+        warn!(
+            "'{}' doesn't look like a monospace font, columns may not line up. Consider using a monospace font instead.",
+            font_name
+        );
+    }
+
+    // Scaling to fit stops at the floor, beyond which long lines are wrapped or the canvas widened
+    let mut size = cli.size;
+    let min_scale = match (cli.overflow, cli.min_font_size, size.as_mut()) {
+        (Overflow::Scale, Some(min), Some(size)) if min < font_size => {
+            let min_scale = min / font_size;
+            let column_width = font.metrics('0', font_size).advance_width * min_scale;
+            let longest = input
+                .contents
+                .lines()
+                .map(|line| line.chars().count())
+                .max()
+                .unwrap_or(0);
+
+            if longest as f32 * column_width > size.width as f32 {
+                match cli.min_font_fallback {
+                    MinFontFallback::Wrap => {
+                        let columns = (size.width as f32 / column_width) as usize;
+                        wrap_input(&mut input, columns);
+                    }
+                    MinFontFallback::Widen => {
+                        size.width = (longest as f32 * column_width).ceil() as u32;
+                    }
+                }
+            }
+            Some(min_scale)
+        }
+        _ => None,
+    };
+
+    // Only the lines wider than the canvas are broken up, everything else stays on one row
+    if let Overflow::Wrap = cli.overflow {
+        let column_width = font.metrics('0', font_size).advance_width;
+        let columns = cli
+            .columns
+            .or_else(|| size.map(|size| (size.width as f32 / column_width) as usize));
+        match columns {
+            Some(columns) => wrap_input(&mut input, columns),
+            None => warn!("--overflow wrap needs --columns or --size to know where to break lines"),
+        }
+    }
+
+    // A width in pixels is however many columns of the font fit in it
+    if let Some(px) = cli.max_width {
+        let column_width = font.metrics('0', font_size).advance_width;
+        wrap_input(&mut input, (px as f32 / column_width) as usize);
+    }
+
+    // The offsets point into the file as it was, which breaking lines up moves everything after
+    if cli.select.is_some() && input.continued.iter().any(Option::is_some) {
+        return Err(SiaError::InvalidConfig(
+            "--select can't be combined with wrapping, which moves the offsets it points at".into(),
+        )
+        .into());
+    }
+
+    // Anything the font can't draw comes out blank (or as tofu), so point it out
+    let (missing_box, missing): (Vec<char>, Vec<char>) = missing_glyphs(&font, &input.contents)
+        .into_iter()
+        .partition(|&c| is_box_drawing(c));
+
+    // Box drawing is common enough in TUI output that it gets routed to a font known to have it
+    let fallbacks = if missing_box.is_empty() {
+        Vec::new()
+    } else {
+        let fallbacks = match &cli.box_font {
+            Some(family) => vec![family.clone()],
+            None => BOX_DRAWING_FALLBACKS
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+        };
+        info!(
+            "'{}' lacks box drawing characters, falling back to {}",
+            font_name,
+            fallbacks.join(", ")
+        );
+        fallbacks
+    };
+
+    if !missing.is_empty() {
+        let mut listed: Vec<String> = missing
+            .iter()
+            .take(MAX_MISSING_LISTED)
+            .map(|&c| format!("'{}' (U+{:04X})", c, c as u32))
+            .collect();
+        if missing.len() > MAX_MISSING_LISTED {
+            listed.push(format!("{} more", missing.len() - MAX_MISSING_LISTED));
+        }
+
+        if cli.strict_coverage {
+            return Err(SiaError::LatinDetect(format!(
+                "'{}' has no glyphs for {}",
+                font_name,
+                listed.join(", ")
+            ))
+            .into());
+        }
+        warn!(
+            "'{}' has no glyphs for {}, these won't render. Consider a font with wider coverage.",
+            font_name,
+            listed.join(", ")
+        );
+    }
+
+    let font_config = FontConfig {
+        glyphs: font,
+        family: font_name.clone(),
+        data: font_bytes,
+        size: font_size,
+        weight: cli.font_weight,
+        fallbacks,
+        line_height: cli.line_height.map_or(1.0, |ScaleFactor(f)| f),
+    };
+    let colors = Colors::from_cli(&cli);
+    let layout = Layout {
+        size,
+        min_scale,
+        diff_added,
+        line_origins,
+        ..Layout::from_cli(&cli)
+    };
+
+    // Stays text all the way through, so none of the drawing below applies
+    if let OutputFormat::Html = format {
+        let html = code_to_html(theme, syntaxes, &input, &font_config, &colors, &layout);
+        write_atomic(&output, html.as_bytes())?;
+        return Ok(());
+    }
+
+    // Get our svg and final width/height measurements
+    let render_input =
+        |input: &Input| code_to_svg(theme, syntaxes, input, &font_config, &colors, &layout);
+    let label = |path: &PathBuf| {
+        path.file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    };
+    let svg = match (&before, &diff_removed) {
+        (Some(before), Some(removed)) => {
+            // The before side tints what was removed instead of what was added
+            let before_svg = code_to_svg(
+                theme,
+                syntaxes,
+                before,
+                &font_config,
+                &Colors {
+                    diff_added: Some(cli.diff_removed_color.unwrap_or(DIFF_REMOVED_COLOR)),
+                    ..colors.clone()
+                },
+                &Layout {
+                    diff_added: Some(removed.clone()),
+                    ..layout.clone()
+                },
+            )?;
+            sheet_svg(
+                vec![
+                    (before_svg, label(&cli.compare[0])),
+                    (render_input(&input)?, label(&cli.compare[1])),
+                ],
+                match cli.compare_layout {
+                    CompareLayout::Side => 2,
+                    CompareLayout::Stacked => 1,
+                },
+                theme,
+                &font_config,
+            )
+        }
+        // Each file highlighted as its own language, stacked under its name
+        _ if !cli.concat.is_empty() => {
+            let mut panels = vec![(render_input(&input)?, label(&cli.concat[0]))];
+            for path in &cli.concat[1..] {
+                let mut segment = parse_to_input(&path.to_string_lossy())?;
+                prepare_input(&cli, &mut segment)?;
+                fall_back_language(&cli, syntaxes, &mut segment);
+                panels.push((render_input(&segment)?, label(path)));
+            }
+            sheet_svg(panels, 1, theme, &font_config)
+        }
+        // A sheet of the same code in each font, each labeled with its name
+        _ if !cli.fonts.is_empty() => {
+            let mut panels = Vec::with_capacity(cli.fonts.len());
+            for name in &cli.fonts {
+                let name = &resolve_family(&cli, &ctx.fontdb, name)?;
+                let (glyphs, data) = load_font(&cli, &ctx.fontdb, name)?;
+                let font = FontConfig {
+                    glyphs,
+                    family: name.clone(),
+                    data,
+                    size: font_size,
+                    weight: cli.font_weight,
+                    fallbacks: font_config.fallbacks.clone(),
+                    line_height: font_config.line_height,
+                };
+                let panel = code_to_svg(theme, syntaxes, &input, &font, &colors, &layout)?;
+                panels.push((panel, name.clone()));
+            }
+            sheet_svg(panels, 1, theme, &font_config)
+        }
+        _ => render_input(&input)?,
+    };
+
+    let report = |stage: RenderStage| {
+        if let Some(progress) = &layout.progress {
+            progress(stage);
+        }
+    };
+    report(RenderStage::SvgBuilt);
+
+    let (width, height) = get_dimensions(&svg);
+
+    // Stderr keeps stdout clean for piping (e.g. raw output to -)
+    if cli.stats {
+        let (final_width, final_height) = match cli.raster_size {
+            Some(size) if !matches!(format, OutputFormat::Svg) => (size.width, size.height),
+            _ => (width, height),
+        };
+
+        eprintln!("lines:    {}", input.contents.lines().count());
+        eprintln!("language: {}", detect_language(syntaxes, &input.ext));
+        eprintln!("theme:    {}", theme.name.as_deref().unwrap_or(theme_name));
+        eprintln!("font:     {}", font_name);
+        eprintln!("size:     {}x{}", final_width, final_height);
+    }
+
+    let svg = serialize(&svg);
+
+    let is_svg = matches!(format, OutputFormat::Svg);
+
+    if is_svg && !cli.output_sizes.is_empty() {
+        warn!("SVG output is resolution independent, ignoring --output-sizes");
+    }
+
+    if is_svg && cli.onto.is_some() {
+        warn!("--onto composites a raster image, ignoring it for SVG output");
+    }
+
+    if cli.outline_text && !is_svg {
+        warn!("--outline-text only applies to SVG output, ignoring it");
+    }
+
+    if is_svg && cli.checkerboard_preview {
+        warn!("--checkerboard-preview only applies to raster output, ignoring it");
+    }
+
+    if cli.embed_metadata && !matches!(format, OutputFormat::Png) {
+        warn!("--embed-metadata only applies to PNG output, ignoring it");
+    }
+
+    // Text chunks that travel with the file, describing how it was made
+    let metadata = if cli.embed_metadata {
+        vec![
+            ("Software", format!("sia {}", env!("CARGO_PKG_VERSION"))),
+            (
+                "Creation Time",
+                humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            ),
+            (
+                "Theme",
+                theme.name.clone().unwrap_or_else(|| theme_name.to_string()),
+            ),
+            ("Font", font_name.clone()),
+            ("Language", detect_language(syntaxes, &input.ext)),
+        ]
+    } else {
+        Vec::new()
+    };
+
+    // Keep the text live so it stays selectable and searchable
+    if is_svg && !cli.outline_text {
+        write_atomic(&output, svg.as_bytes())?;
+        return Ok(());
+    }
+
+    let tree = usvg::Tree::from_str(&svg, &tree_options)?;
+
+    // usvg has already shaped every glyph into a path, so writing the tree back out gives pure geometry
+    if is_svg {
+        write_atomic(
+            &output,
+            tree.to_string(&usvg::WriteOptions::default()).as_bytes(),
+        )?;
+        return Ok(());
+    }
+
+    // Crops away the dead space an overestimated canvas leaves behind
+    let trim = |map: tiny_skia::Pixmap, padding: u32| {
+        if cli.trim {
+            trim_pixmap(&map, padding).unwrap_or(map)
+        } else {
+            map
+        }
+    };
+
+    // Physical size for print and layout tools, matching the DPI point sizes are converted at
+    let output_dpi = cli.output_dpi.unwrap_or(tree_options.dpi);
+
+    // The raster doesn't have to match the document's logical size, the content is fit into it
+    let (raster_width, raster_height) = match cli.raster_size {
+        Some(size) => (size.width, size.height),
+        None => (width, height),
+    };
+    // A zero on either side leaves no ratio to fit by, so it renders at its own scale
+    let ratio = |raster: u32, document: u32| match raster > 0 && document > 0 {
+        true => raster as f32 / document as f32,
+        false => 1.0,
+    };
+    let fit = ratio(raster_width, width).min(ratio(raster_height, height));
+    let scaled = |v: u32, factor: f32| (v as f32 * factor).ceil() as u32;
+
+    // One pass over the tree per size, highlighting and layout already happened above
+    if !cli.output_sizes.is_empty() {
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = output.extension().unwrap_or_default().to_string_lossy();
+
+        for &ScaleFactor(factor) in &cli.output_sizes {
+            let path = output.with_file_name(format!("{}@{}x.{}", stem, factor, ext));
+            let map = rasterize(
+                &tree,
+                scaled(raster_width, factor),
+                scaled(raster_height, factor),
+                fit * factor,
+            )?;
+            report(RenderStage::Rasterized);
+            let map = trim(map, scaled(cli.trim_padding, factor));
+            if cli.checkerboard_preview {
+                save_preview(&map, &path, output_dpi * factor)?;
+            }
+            save_pixmap(
+                &map,
+                &path,
+                format,
+                &metadata,
+                // The same physical size, at a higher density
+                output_dpi * factor,
+                cli.alpha,
+            )?;
+        }
+
+        return Ok(());
+    }
+
+    let map = trim(
+        rasterize(&tree, raster_width, raster_height, fit)?,
+        cli.trim_padding,
+    );
+    report(RenderStage::Rasterized);
+
+    // Blended over the base, so transparent backgrounds and margins let it show through
+    let map = match &cli.onto {
+        Some(base) => {
+            let mut canvas = tiny_skia::Pixmap::load_png(base).map_err(|e| {
+                SiaError::InvalidConfig(format!("couldn't load {} ({})", base.display(), e))
+            })?;
+            canvas.draw_pixmap(
+                cli.at.x,
+                cli.at.y,
+                map.as_ref(),
+                &tiny_skia::PixmapPaint::default(),
+                tiny_skia::Transform::identity(),
+                None,
+            );
+            canvas
+        }
+        None => map,
+    };
+    if cli.checkerboard_preview {
+        save_preview(&map, &output, output_dpi)?;
+    }
+    save_pixmap(&map, &output, format, &metadata, output_dpi, cli.alpha)?;
+
+    Ok(())
+}
+
+/// Renders the input (or a sample) once per theme, or once per syntax, and tiles the results into a sheet at `out`.
+fn render_gallery(
+    cli: &Cli,
+    ctx: &RenderContext,
+    out: &Path,
+    syntaxes: bool,
+    max: usize,
+    per_row: usize,
+) -> Result<(), Error> {
+    let mut input = match cli.input.first() {
+        Some(input) => input.clone(),
+        None => literal_input(GALLERY_SAMPLE),
+    };
+    prepare_input(cli, &mut input)?;
+    if cli.input.is_empty() {
+        input.ext = "rs".into();
+    }
+
+    let font_name = cli
+        .font
+        .clone()
+        .or_else(|| ctx.file_family.clone())
+        .ok_or_else(|| SiaError::InvalidConfig("the gallery needs --font or --font-file".into()))?;
+    let font_name = resolve_family(cli, &ctx.fontdb, &font_name)?;
+    let font_size = cli.font_size.unwrap_or(FontSize::Px(14.0)).to_px(cli.dpi);
+    let (glyphs, data) = load_font(cli, &ctx.fontdb, &font_name)?;
+    let font = FontConfig {
+        glyphs,
+        family: font_name,
+        data,
+        size: font_size,
+        weight: cli.font_weight,
+        fallbacks: Vec::new(),
+        line_height: cli.line_height.map_or(1.0, |ScaleFactor(f)| f),
+    };
+    let colors = Colors::from_cli(cli);
+    let layout = Layout {
+        progress: None,
+        ..Layout::from_cli(cli)
+    };
+
+    let theme = resolve_theme(
+        &ctx.themes,
+        cli.theme.as_deref().unwrap_or("base16-ocean.dark"),
+    )?;
+
+    let mut panels = Vec::new();
+    if syntaxes {
+        // The input is relabeled as each syntax, by extension where it has one
+        for syntax in ctx.syntaxes.syntaxes().iter().take(max) {
+            let tile = Input {
+                ext: syntax
+                    .file_extensions
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| syntax.name.clone()),
+                ..input.clone()
+            };
+            let panel = code_to_svg(theme, &ctx.syntaxes, &tile, &font, &colors, &layout)?;
+            panels.push((panel, syntax.name.clone()));
+        }
+    } else {
+        for name in ctx.themes.names().into_iter().take(max) {
+            let tile_theme = resolve_theme(&ctx.themes, &name)?;
+            let panel = code_to_svg(tile_theme, &ctx.syntaxes, &input, &font, &colors, &layout)?;
+            panels.push((panel, name));
+        }
+    }
+
+    let svg = serialize(&sheet_svg(panels, per_row, theme, &font));
+    let format = OutputFormat::from_extension(out)?.unwrap_or(OutputFormat::Png);
+    match format {
+        OutputFormat::Svg => {
+            write_atomic(out, svg.as_bytes())?;
+            return Ok(());
+        }
+        OutputFormat::Html => {
+            return Err(SiaError::InvalidConfig("a gallery can't be written as HTML".into()).into())
+        }
+        _ => {}
+    }
+
+    let tree_options = usvg::Options {
+        fontdb: Arc::clone(&ctx.fontdb),
+        dpi: cli.dpi,
+        font_family: font.family.clone(),
+        font_size,
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_str(&svg, &tree_options)?;
+    let size = tree.size().to_int_size();
+    let map = rasterize(&tree, size.width(), size.height(), 1.0)?;
+    save_pixmap(&map, out, format, &[], cli.dpi, cli.alpha)
+}
+
+/// The CLI's own progress, a running count on stderr for inputs big enough to take a while to highlight.
+fn report_progress(stage: RenderStage) {
+    match stage {
+        RenderStage::Highlighting { total } if total >= PROGRESS_MIN_LINES => {
+            eprint!("\rHighlighting 0/{} lines", total);
+        }
+        RenderStage::Highlighted { line, total } if total >= PROGRESS_MIN_LINES => {
+            if line == total {
+                eprintln!("\rHighlighting {}/{} lines", line, total);
+            } else if line % 100 == 0 {
+                eprint!("\rHighlighting {}/{} lines", line, total);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Renders the input `per_page` lines at a time, one image per page named after the output.
+/// Each page is an ordinary --lines render, so line numbers carry on from the page before.
+fn paginate(cli: Cli, ctx: &RenderContext, input: Input, per_page: usize) -> Result<(), Error> {
+    let mut prepared = input.clone();
+    prepare_input(&cli, &mut prepared)?;
+    let total = prepared.contents.lines().count().max(1);
+
     let output = cli
         .output
         .clone()
-        .unwrap_or_else(|| PathBuf::from("output").with_extension("png"));
+        .unwrap_or_else(|| PathBuf::from("output"));
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = match (output.extension(), cli.format) {
+        (Some(ext), _) => ext.to_string_lossy().into_owned(),
+        (None, Some(format)) => format.to_possible_value().unwrap().get_name().to_string(),
+        (None, None) => "png".into(),
+    };
 
-    // TODO: This only includes three themes, so I'm going to offer an option for users to load their own, just need to see how they're defined.
-    let available_themes: LazyThemeSet = LazyThemeSet::from(extra());
+    for (page, start) in (1..=total).step_by(per_page).enumerate() {
+        let path = output.with_file_name(format!("{}-{}.{}", stem, page + 1, ext));
+        render(
+            Cli {
+                input: vec![input.clone()],
+                lines: vec![LineRange {
+                    start,
+                    end: (start + per_page - 1).min(total),
+                }],
+                paginate: None,
+                output: Some(path),
+                ..cli.clone()
+            },
+            ctx,
+        )?;
+    }
 
-    let font_name = &cli.font;
+    Ok(())
+}
 
-    // Setup the rendering
-    tree_options.dpi = 300.0;
-    tree_options.font_family = font_name.clone();
-    tree_options.font_size = cli.font_size;
+/// The family name as the font database knows it. Falls back to ignoring case, then to dropping a
+/// trailing style word like "Medium", and lists similar families when nothing matches.
+fn resolve_family(cli: &Cli, fontdb: &Database, font_name: &str) -> Result<String, SiaError> {
+    let families = || {
+        fontdb
+            .faces()
+            .flat_map(|face| face.families.iter().map(|f| &f.0))
+    };
 
+    // A PostScript name (like FiraCode-Retina) is taken as is, load_font looks those up itself
+    if families().any(|family| family == font_name)
+        || fontdb
+            .faces()
+            .any(|face| face.post_script_name == font_name)
+    {
+        return Ok(font_name.to_string());
+    }
+
+    let stripped = strip_font_modifier(font_name);
+    for candidate in [font_name, stripped.as_str()] {
+        if let Some(family) = families().find(|family| family.eq_ignore_ascii_case(candidate)) {
+            info!("Font '{}' not found, using '{}'", font_name, family);
+            return Ok(family.clone());
+        }
+    }
+
+    // Families sharing a word with the request, e.g. "Fira Mono" for "Fira Cod"
+    let words: Vec<String> = font_name
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+    let mut similar: Vec<&String> = families()
+        .filter(|family| {
+            family
+                .split_whitespace()
+                .any(|word| words.contains(&word.to_lowercase()))
+        })
+        .collect();
+    similar.sort();
+    similar.dedup();
+
+    let hint = if cli.no_system_fonts {
+        " (system fonts are disabled, pass it with --font-file or --font-dir)".to_string()
+    } else if similar.is_empty() {
+        String::new()
+    } else {
+        let listed: Vec<String> = similar
+            .iter()
+            .take(MAX_FONT_SUGGESTIONS)
+            .map(|family| format!("'{}'", family))
+            .collect();
+        format!(", did you mean {}?", listed.join(", "))
+    };
+    Err(SiaError::FontNameDetect(format!(
+        "font '{}' not found{}",
+        font_name, hint
+    )))
+}
+
+/// Finds the face `font_name` refers to, returning it loaded for measuring along with its file's bytes.
+fn load_font(cli: &Cli, fontdb: &Database, font_name: &str) -> Result<(Font, Vec<u8>), Error> {
     // Get the font_face
-    let font_face = tree_options
-        .fontdb_mut()
-        .faces()
-        .find(|face| face.families.iter().any(|family| family.0.eq(&cli.font)))
-        .ok_or("Font not found")
-        .unwrap();
+    let font_face = match cli.font_weight {
+        // Measure with the face closest to the requested weight, the same one usvg will draw with
+        Some(weight) => fontdb
+            .query(&Query {
+                families: &[Family::Name(font_name)],
+                weight: Weight(weight),
+                ..Default::default()
+            })
+            .and_then(|id| fontdb.face(id)),
+        None => fontdb
+            .faces()
+            .find(|face| face.families.iter().any(|family| family.0.eq(font_name))),
+    }
+    // A PostScript name (like FiraCode-Retina) already pins down one exact face
+    .or_else(|| {
+        fontdb
+            .faces()
+            .find(|face| face.post_script_name.eq(font_name))
+    })
+    .ok_or_else(|| {
+        let hint = if cli.no_system_fonts {
+            " (system fonts are disabled, pass it with --font-file or --font-dir)"
+        } else {
+            ""
+        };
+        SiaError::FontLoad(format!("font '{}' not found{}", font_name, hint))
+    })?;
+
+    // A collection (.ttc) holds several faces in one file, any of which can be picked by position
+    let font_face = match cli.font_index {
+        Some(index) => fontdb
+            .faces()
+            .find(|face| face.index == index && same_source(&face.source, &font_face.source))
+            .ok_or_else(|| {
+                SiaError::FontLoad(format!(
+                    "the file holding '{}' has no face at index {}",
+                    font_name, index
+                ))
+            })?,
+        None => font_face,
+    };
 
     // Get the underlying font source data
     let font_bytes = match &font_face.source {
@@ -294,47 +3176,170 @@ fn run() -> Result<(), Error> {
     };
 
     // Assign data to a fontdue font
+    // fontdue's scale only tunes the outlines it rasterizes, which never happens here. Metrics are
+    // scaled by the px given on each call, so the SVG font size is passed there instead
     let font = Font::from_bytes(
         font_bytes.clone(),
         fontdue::FontSettings {
-            collection_index: 0,
-            scale: cli.font_size,
+            collection_index: font_face.index,
             load_substitutions: true,
+            ..Default::default()
         },
     )
     .expect("We can assume that if the data came from a font already loaded, it's valid");
 
-    // Get our svg and final width/height measurements
-    let svg = code_to_svg(
-        available_themes.get(&cli.theme).unwrap(),
-        &cli.input,
-        &FontConfig {
-            glyphs: font,
-            data: font_bytes,
-            size: cli.font_size,
-        },
-        &Colors {
-            background_alpha: cli.bg_alpha,
-            foreground_alpha: cli.fg_alpha,
-        },
-    )?;
+    Ok((font, font_bytes))
+}
 
-    let (width, height) = get_dimensions(&svg);
+/// Writes `map` over a checkerboard as a PNG beside `path`, so transparent areas are told apart from white ones.
+fn save_preview(map: &tiny_skia::Pixmap, path: &Path, dpi: f32) -> Result<()> {
+    let mut preview = tiny_skia::Pixmap::new(map.width(), map.height())
+        .ok_or_else(|| SiaError::InvalidConfig("can't make an empty preview".into()))?;
+    preview.fill(tiny_skia::Color::WHITE);
+
+    let mut paint = tiny_skia::Paint::default();
+    paint.set_color_rgba8(0xCC, 0xCC, 0xCC, 0xFF);
+    let square = CHECKERBOARD_SQUARE;
+    for row in 0..map.height().div_ceil(square) {
+        for column in (row % 2..map.width().div_ceil(square)).step_by(2) {
+            if let Some(rect) = tiny_skia::Rect::from_xywh(
+                (column * square) as f32,
+                (row * square) as f32,
+                square as f32,
+                square as f32,
+            ) {
+                preview.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
+            }
+        }
+    }
+    preview.draw_pixmap(
+        0,
+        0,
+        map.as_ref(),
+        &tiny_skia::PixmapPaint::default(),
+        tiny_skia::Transform::identity(),
+        None,
+    );
 
-    let svg = svg.to_string().replace('\n', "");
-    let tree = usvg::Tree::from_str(&svg, &tree_options)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let preview_path = path.with_file_name(format!("{}-preview.png", stem));
+    save_pixmap(&preview, &preview_path, OutputFormat::Png, &[], dpi, None)
+}
+
+/// Writes a rendered pixmap as PNG, JPEG or WebP, or as raw pixels for `OutputFormat::Raw`
+///
+/// Raw output is an 8 byte header (width then height, each a little-endian u32) followed by
+/// width * height RGBA8 pixels, row by row from the top left, with premultiplied alpha unless `alpha` says otherwise.
+/// A path of `-` writes it to stdout.
+///
+/// `metadata` is written into PNGs as tEXt chunks, and ignored for raw output.
+fn save_pixmap(
+    map: &tiny_skia::Pixmap,
+    path: &Path,
+    format: OutputFormat,
+    metadata: &[(&str, String)],
+    dpi: f32,
+    alpha: Option<AlphaMode>,
+) -> Result<()> {
+    // tiny-skia keeps the color channels premultiplied, which only raw output leaves alone by default
+    let pixels = match (alpha, format) {
+        (Some(AlphaMode::Straight), _) | (None, OutputFormat::Png | OutputFormat::Webp) => map
+            .pixels()
+            .iter()
+            .flat_map(|p| {
+                let c = p.demultiply();
+                [c.red(), c.green(), c.blue(), c.alpha()]
+            })
+            .collect(),
+        _ => map.data().to_vec(),
+    };
+
+    // JPEG has no alpha, so whatever's transparent shows white, as image viewers tend to show it
+    // Premultiplied channels never exceed their alpha, so adding the white left uncovered can't overflow
+    if let OutputFormat::Jpeg = format {
+        let rgb: Vec<u8> = map
+            .pixels()
+            .iter()
+            .flat_map(|p| {
+                let under = 0xFF - p.alpha();
+                [p.red() + under, p.green() + under, p.blue() + under]
+            })
+            .collect();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).write_image(
+            &rgb,
+            map.width(),
+            map.height(),
+            ColorType::Rgb8,
+        )?;
+        write_atomic(path, &jpeg)?;
+        return Ok(());
+    }
+
+    if let OutputFormat::Webp = format {
+        let mut webp = Vec::new();
+        WebPEncoder::new_lossless(&mut webp).write_image(
+            &pixels,
+            map.width(),
+            map.height(),
+            ColorType::Rgba8,
+        )?;
+        write_atomic(path, &webp)?;
+        return Ok(());
+    }
 
-    let mut map = tiny_skia::Pixmap::new(width, height).unwrap();
+    // tiny-skia can't write text or pHYs chunks, so encode by hand
+    if !matches!(format, OutputFormat::Raw) {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, map.width(), map.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        // pHYs counts pixels per meter
+        let ppm = (dpi / 0.0254).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: ppm,
+            yppu: ppm,
+            unit: png::Unit::Meter,
+        }));
+        for (keyword, text) in metadata {
+            encoder.add_text_chunk(keyword.to_string(), text.clone())?;
+        }
+
+        encoder.write_header()?.write_image_data(&pixels)?;
+        write_atomic(path, &png)?;
+        return Ok(());
+    }
+
+    let mut bytes = Vec::with_capacity(8 + pixels.len());
+    bytes.extend_from_slice(&map.width().to_le_bytes());
+    bytes.extend_from_slice(&map.height().to_le_bytes());
+    bytes.extend_from_slice(&pixels);
+
+    if path == Path::new("-") {
+        io::stdout().write_all(&bytes)?;
+    } else {
+        write_atomic(path, &bytes)?;
+    }
+
+    Ok(())
+}
+
+/// Renders the tree scaled by `factor` into a `width`x`height` pixmap, centered if there's room to spare
+fn rasterize(tree: &usvg::Tree, width: u32, height: u32, factor: f32) -> Result<tiny_skia::Pixmap> {
+    let mut map = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| SiaError::InvalidConfig(format!("can't render at {}x{}", width, height)))?;
+
+    let size = tree.size();
+    let dx = (width as f32 - size.width() * factor).max(0.0) / 2.0;
+    let dy = (height as f32 - size.height() * factor).max(0.0) / 2.0;
 
     resvg::render(
-        &tree,
-        tiny_skia_path::Transform::default(),
+        tree,
+        tiny_skia_path::Transform::from_scale(factor, factor).post_translate(dx, dy),
         &mut map.as_mut(),
     );
 
-    map.save_png(&output)?;
-
-    Ok(())
+    Ok(map)
 }
 
 fn strip_font_modifier(s: &str) -> String {
@@ -375,3 +3380,153 @@ fn strip_font_modifier(s: &str) -> String {
     // Otherwise, return original
     s.to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_input_strips_a_leading_bom() {
+        let input = bytes_input(b"\xEF\xBB\xBFfn main() {}\n".to_vec(), "rs".into(), None);
+        assert_eq!(input.contents, "fn main() {}\n");
+        assert!(input.valid_utf8);
+    }
+
+    #[test]
+    fn bytes_input_keeps_a_bom_past_the_start() {
+        let input = bytes_input("a\u{FEFF}b".as_bytes().to_vec(), "txt".into(), None);
+        assert_eq!(input.contents, "a\u{FEFF}b");
+    }
+
+    #[test]
+    fn a_bom_prefixed_file_renders_without_it() {
+        let dir = std::env::temp_dir().join("sia-bom");
+        fs::create_dir_all(&dir).unwrap();
+
+        for (name, contents, ext) in [
+            ("main.rs", "fn main() {}\n", "rs"),
+            ("run", "#!/bin/sh\necho hi\n", "sh"),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, format!("\u{FEFF}{}", contents)).unwrap();
+            let path = path.to_string_lossy();
+
+            let input = parse_to_input(&path).unwrap();
+            assert_eq!(input.ext, ext, "{}", name);
+            assert_eq!(input.contents, contents);
+
+            let output = dir.join(format!("{}.svg", name));
+            let output = output.to_string_lossy();
+            let cli = parse(&["--input", &path, "--output", &output]);
+            let ctx = RenderContext::new(&cli).unwrap();
+            render(cli, &ctx).unwrap();
+
+            // The first segment drawn is the start of the code, with nothing in front of it
+            let svg = fs::read_to_string(output.as_ref()).unwrap();
+            let doc = roxmltree::Document::parse(&svg).unwrap();
+            let first = doc
+                .descendants()
+                .find(|n| n.has_tag_name("tspan"))
+                .and_then(|n| n.text())
+                .expect("nothing was drawn");
+            assert!(!first.contains('\u{FEFF}'), "{:?}", first);
+            assert!(contents.starts_with(first.trim_end()), "{:?}", first);
+        }
+    }
+
+    #[test]
+    fn html_refuses_what_only_the_image_can_draw() {
+        let output = std::env::temp_dir().join("sia-refused.html");
+        let output = output.to_string_lossy();
+        for args in [
+            &["--line-numbers"][..],
+            &["--highlight-lines", "1"],
+            &["--fg-alpha", "0.5"],
+            &["--annotate", "1=note"],
+        ] {
+            let mut args = args.to_vec();
+            args.extend(["--input", "a", "--output", &output]);
+            let cli = parse(&args);
+            let ctx = RenderContext::new(&cli).unwrap();
+            let err = render(cli, &ctx).unwrap_err().to_string();
+            assert!(err.contains("can't be written as HTML"), "{}", err);
+            assert!(err.contains(args[0]), "{}", err);
+        }
+    }
+
+    /// Parses `args` as sia's command line, drawing in the first family the system has.
+    fn parse(args: &[&str]) -> Cli {
+        let mut fontdb = Database::new();
+        fontdb.load_system_fonts();
+        let family = fontdb
+            .faces()
+            .find_map(|face| face.families.first())
+            .map(|family| family.0.clone())
+            .expect("no system fonts to render with");
+        let base = ["sia", "--font", &family, "--font-size", "14"];
+        Cli::try_parse_from(base.iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn a_100k_character_line_needs_a_bounded_width() {
+        let line = "x".repeat(100_000);
+        let cli = parse(&["--input", &line]);
+        let ctx = RenderContext::new(&cli).unwrap();
+        let err = render(cli, &ctx).unwrap_err().to_string();
+        assert!(err.contains("line 1 is over"), "{}", err);
+
+        // Broken up, it's just a lot of rows
+        let output = std::env::temp_dir().join("sia-long-line.svg");
+        let output = output.to_string_lossy();
+        let cli = parse(&["--input", &line, "--wrap", "100", "--output", &output]);
+        let ctx = RenderContext::new(&cli).unwrap();
+        render(cli, &ctx).unwrap();
+    }
+
+    #[test]
+    fn extensionless_files_are_sniffed() {
+        let dir = std::env::temp_dir().join("sia-extensionless");
+        fs::create_dir_all(&dir).unwrap();
+        let ext = |contents: &str| {
+            let path = dir.join("README");
+            fs::write(&path, contents).unwrap();
+            parse_to_input(&path.to_string_lossy()).unwrap().ext
+        };
+
+        assert_eq!(ext("Just some notes, nothing more\n"), "txt");
+        assert_eq!(ext("#!/bin/sh\necho hi\n"), "sh");
+        assert_eq!(guess_ext(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "png");
+    }
+
+    #[test]
+    fn family_and_postscript_names_load_the_same_face() {
+        let mut fontdb = Database::new();
+        fontdb.load_system_fonts();
+        // The first face of its family, which is the one a bare family name picks
+        let face = fontdb
+            .faces()
+            .find(|face| {
+                let family = &face.families[0].0;
+                *family != face.post_script_name
+                    && fontdb
+                        .faces()
+                        .find(|other| other.families.iter().any(|f| f.0 == *family))
+                        .is_some_and(|first| first.id == face.id)
+            })
+            .expect("no system font with a distinct PostScript name");
+
+        let cli = parse(&["--input", "a"]);
+        let (by_family, family_bytes) = load_font(&cli, &fontdb, &face.families[0].0).unwrap();
+        let (by_postscript, postscript_bytes) =
+            load_font(&cli, &fontdb, &face.post_script_name).unwrap();
+        assert!(family_bytes == postscript_bytes);
+        assert_eq!(by_family.file_hash(), by_postscript.file_hash());
+    }
+
+    #[test]
+    fn parse_to_input_rejects_a_directory() {
+        let dir = std::env::temp_dir();
+        let err = parse_to_input(&dir.to_string_lossy()).unwrap_err();
+        assert!(matches!(err, SiaError::InvalidConfig(_)), "{}", err);
+    }
+}
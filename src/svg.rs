@@ -1,141 +1,2000 @@
 // Code for generating the svg file
 
 use anyhow::{Error, Result};
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
 use svg::node::element::{
-    ClipPath, Definitions, Filter, FilterEffectGaussianBlur, FilterEffectMerge,
-    FilterEffectMergeNode, FilterEffectOffset, Group, Rectangle, TSpan, Text,
+    Circle, ClipPath, Definitions, Filter, FilterEffectComposite, FilterEffectFlood,
+    FilterEffectGaussianBlur, FilterEffectMerge, FilterEffectMergeNode, FilterEffectOffset, Group,
+    Line, LinearGradient, Path, Pattern, RadialGradient, Rectangle, Stop, TSpan, Text,
 };
 use svg::Document;
 use svg::Node;
 use two_face::re_exports::syntect::easy::HighlightLines;
-use two_face::re_exports::syntect::highlighting::{Style, Theme};
-use two_face::re_exports::syntect::parsing::SyntaxSet;
+use two_face::re_exports::syntect::highlighting::{Color, FontStyle, Highlighter, Style, Theme};
+use two_face::re_exports::syntect::parsing::{
+    ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet,
+};
 use two_face::re_exports::syntect::util::LinesWithEndings;
 
-use crate::utils::get_canvas_height;
-use crate::{Colors, FontConfig, Input};
+use crate::utils::{
+    boost_contrast, ensure_contrast, first_baseline, get_canvas_height, is_zero_width, line_pitch,
+    path_components, relative_luminance,
+};
+use crate::{
+    AspectRatio, BgPattern, ByteRange, Chrome, Colors, Continuation, FontConfig, HexColor, Input,
+    Layout, Overflow, PreserveAspect, RenderStage, ShadowOffset, ShowWhitespace, SiaError,
+    SpaceMode, StyleOverride, TextAlign,
+};
+
+// The theme looks unsupported when at least this share of tokens is left in the default color
+const UNSTYLED_WARN_RATIO: f32 = 0.9;
+// Too few tokens to say anything about the theme
+const UNSTYLED_MIN_TOKENS: usize = 20;
+
+// Baselines, bounds and the size tag drawn by --guides
+const GUIDE_COLOR: &str = "#FF4081";
+
+// How many columns the --max-width-chars fade stretches over
+const FADE_COLUMNS: usize = 3;
+
+// How dark a --shadow gets where it's densest
+const SHADOW_OPACITY: f32 = 0.45;
+
+// Between the directories of a --path-header
+const BREADCRUMB_SEPARATOR: &str = " / ";
 
 pub(crate) fn code_to_svg(
     theme: &Theme,
+    ss: &SyntaxSet,
     source: &Input,
     font: &FontConfig,
     colors: &Colors,
+    layout: &Layout,
 ) -> Result<Document, Error> {
+    // Extract default bg/fg from theme.settings
+    // Themes built for transparency may leave the background out entirely
+    let theme_bg = theme.settings.background.unwrap_or(Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    });
+
+    let bg = background(theme_bg, colors);
+    let fg = theme.settings.foreground.unwrap();
+    let bg_hex = if bg.a == 0xFF {
+        format!("#{:02X}{:02X}{:02X}", bg.r, bg.g, bg.b)
+    } else {
+        format!("#{:02X}{:02X}{:02X}{:02X}", bg.r, bg.g, bg.b, bg.a)
+    };
+    let text_fg = text_foreground(theme, colors);
+    let fg_hex = format!("#{:02X}{:02X}{:02X}", text_fg.r, text_fg.g, text_fg.b);
+    let accents = ThemeAccents::from_theme(theme);
+
     // Prepare highlighter
-    let ss = SyntaxSet::load_defaults_newlines();
     let syntax = ss
         .find_syntax_by_token(&source.ext)
         .unwrap_or_else(|| ss.find_syntax_plain_text());
 
-    let mut highlighter = HighlightLines::new(syntax, theme);
+    // The nonewlines syntaxes expect lines with their endings already cut off
+    let source_lines: Vec<&str> = if layout.no_trailing_style {
+        source.contents.lines().collect()
+    } else {
+        LinesWithEndings::from(&source.contents).collect()
+    };
 
-    // Highlight each line into Vec<(Style, &str)>
-    let lines: Vec<Vec<(Style, &str)>> = LinesWithEndings::from(&source.contents)
-        .map(|ln| highlighter.highlight_line(ln, &ss).unwrap())
-        .collect();
+    let mut lines = highlight(
+        theme,
+        ss,
+        syntax,
+        &source_lines,
+        &source.continued,
+        colors,
+        layout,
+    );
 
-    // Extract default bg/fg from theme.settings
-    let bg = theme.settings.background.unwrap();
-    let fg = theme.settings.foreground.unwrap();
-    let bg_hex = format!("#{:02X}{:02X}{:02X}", bg.r, bg.g, bg.b);
-    let fg_hex = format!("#{:02X}{:02X}{:02X}", fg.r, fg.g, fg.b);
+    // Long lines lose their middle rather than their end, each side keeping its highlighting
+    if let Some(columns) = layout.truncate_middle {
+        let ellipsis = Style {
+            foreground: fg,
+            background: theme_bg,
+            font_style: FontStyle::empty(),
+        };
+        lines = lines
+            .into_iter()
+            .map(|line| truncate_middle(line, columns, ellipsis))
+            .collect();
+    }
+
+    // Themes without rules for a language's scopes leave nearly everything in the default color
+    if !layout.raw && syntax.name != ss.find_syntax_plain_text().name {
+        let tokens = lines
+            .iter()
+            .flatten()
+            .filter(|(_, segment)| !segment.trim().is_empty());
+        let (total, unstyled) = tokens.fold((0, 0), |(total, unstyled), (style, _)| {
+            let plain = style.foreground == fg && style.font_style.is_empty();
+            (total + 1, unstyled + plain as usize)
+        });
+
+        if total >= UNSTYLED_MIN_TOKENS && unstyled as f32 / total as f32 >= UNSTYLED_WARN_RATIO {
+            warn!(
+                "{} of {} tokens use the default color, {} may not support {} well",
+                unstyled,
+                total,
+                theme.name.as_deref().unwrap_or("the theme"),
+                syntax.name
+            );
+        }
+    }
+
+    // A final newline ends the last line rather than starting a new one, unless asked to keep it
+    if layout.preserve_empty_last_line && source.contents.ends_with('\n') {
+        lines.push(Vec::new());
+    }
 
     // a semantic <g> for all text
     let mut g = Group::new()
-        .set("font-family", font.glyphs.name().unwrap())
+        .set("font-family", font_family(font))
         .set("font-size", font.size)
         .set("fill", fg_hex.clone());
 
+    if let Some(weight) = font.weight {
+        g = g.set("font-weight", weight);
+    }
+
+    // Scopes with their own background get a rect behind them, gathered here and drawn under the text
+    let metrics = font.glyphs.horizontal_line_metrics(font.size);
+    let (ascent, descent) = metrics.map_or((font.size, 0.0), |m| (m.ascent, m.descent));
+    let mut token_backgrounds = Vec::new();
+    // Every glyph shares an advance in a monospace font, so any one gives the column width
+    let column_width = font.glyphs.metrics('0', font.size).advance_width;
+    // Code repeats the same few characters endlessly, so each one's advance is only looked up once
+    let advances = RefCell::new(HashMap::new());
+    let advance = |s: &str| -> f32 {
+        let mut advances = advances.borrow_mut();
+        s.chars()
+            .filter(|&c| !is_zero_width(c))
+            .map(|c| {
+                *advances
+                    .entry(c)
+                    .or_insert_with(|| font.glyphs.metrics(c, font.size).advance_width)
+            })
+            // fold rather than sum, which starts from -0.0 and would print as such
+            .fold(0.0, |a, b| a + b)
+    };
+    let mut tab_markers = Vec::new();
+    let mut truncated = Vec::new();
+
+    let line_endings: Vec<&str> = LinesWithEndings::from(&source.contents)
+        .map(|line| &line[line.trim_end_matches(['\r', '\n']).len()..])
+        .collect();
+
+    let mut line_widths = Vec::with_capacity(lines.len());
+    let mut line_texts = Vec::with_capacity(lines.len());
+    let mut contrast_fixes: HashMap<(u8, u8, u8), Option<Color>> = HashMap::new();
+
+    // An emphasized first line is that much bigger, pushing every line below it down by the difference
+    let emphasis = layout.emphasize_first_line.unwrap_or(1.0);
+    let line_scale = |i: usize| if i == 0 { emphasis } else { 1.0 };
+    // Every row, its text and the canvas height all go by the same pitch, so they can't drift apart
+    let pitch = line_pitch(font);
+    let first_baseline = first_baseline(font);
+    let line_top = |i: usize| match i {
+        0 => 0.0,
+        _ => (i as f32 - 1.0 + emphasis) * pitch,
+    };
+    let line_height = |i: usize| pitch * line_scale(i);
+    let baseline = |i: usize| line_top(i) + first_baseline * line_scale(i);
+
+    // Excerpts keep the numbers lines had in the file, while the rows marking a gap get none
+    // --start-line then moves every number along by the same amount
+    // Rows a wrap broke off belong to the line they came from
+    let continued = |i: usize| i > 0 && matches!(source.continued.get(i), Some(Some(_)));
+    let row_sources: Vec<usize> = (0..lines.len())
+        .scan(0, |next, i| {
+            if !continued(i) {
+                *next += 1;
+            }
+            Some(*next - 1)
+        })
+        .collect();
+    let row_lines: Vec<Option<usize>> = row_sources
+        .iter()
+        .map(|&line| {
+            match &layout.line_origins {
+                Some(origins) => origins.get(line).copied().flatten(),
+                None => Some(line + 1),
+            }
+            .map(|n| n + layout.start_line - 1)
+        })
+        .collect();
+    // Only the first row of a wrapped line is numbered
+    let line_numbers: Vec<Option<usize>> = row_lines
+        .iter()
+        .enumerate()
+        .map(|(i, &n)| n.filter(|_| !continued(i)))
+        .collect();
+    // --highlight-lines goes by those same numbers, so it picks out the same lines in an excerpt
+    // and every row of a wrapped one
+    let highlighted = |i: usize| {
+        row_lines
+            .get(i)
+            .copied()
+            .flatten()
+            .is_some_and(|n| layout.highlight_lines.contains(&n))
+    };
+
     let mut max_width = 0;
     for (i, line) in lines.iter().enumerate() {
-        // For some reason 1.2 works better...
-        let y_em = (i + 1) as f64 * 1.2;
+        // Spaces collapse by default, and indentation with them, unless preserved or made unbreakable
+        let mut text = Text::new("").set("x", 0).set("y", baseline(i));
+        if let (0, Some(factor)) = (i, layout.emphasize_first_line) {
+            text = text.set("font-size", font.size * factor);
+        }
+        if let SpaceMode::Preserve = layout.space_mode {
+            text = text.set("xml:space", "preserve");
+        }
 
-        // using space preserve otherwise it leads to even weirder space behavior.
-        let mut text = Text::new("")
-            .set("x", 0)
-            .set("y", format!("{:.2}em", y_em))
-            .set("xml:space", "preserve");
+        // Unchanged lines fade back so the additions stand out, as do the markers for skipped lines
+        let unchanged = layout
+            .diff_added
+            .as_ref()
+            .is_some_and(|added| !added.contains(&i));
+        let elided = layout
+            .line_origins
+            .as_ref()
+            .is_some_and(|origins| origins.get(row_sources[i]) == Some(&None));
+        let dimmed = !layout.highlight_lines.is_empty() && !highlighted(i);
+        if unchanged || elided || dimmed {
+            text = text.set("opacity", 0.5);
+        }
 
         let mut segments = String::new();
+        let mut column = 0;
 
         for &(ref style, segment) in line {
+            let expanded = expand_tabs(segment, layout.tab_width, &mut column);
+            let segment = expanded.as_str();
             // Check if there is style information for the current segment.
             let unstyled = style.foreground == fg && style.font_style.is_empty();
 
-            let mut t = TSpan::new(segment);
+            let mut t = TSpan::new(space_text(segment, layout.space_mode));
 
-            // Only apply the fill if there is style information
-            if !unstyled {
-                t = t.set(
-                    "fill",
-                    format!(
-                        "#{:02X}{:02X}{:02X}{:02X}", // Ensure that each RGB value converts accurately to a HEX
-                        style.foreground.r,
-                        style.foreground.g,
-                        style.foreground.b,
-                        colors.foreground_alpha.to_u8()
-                    ),
-                );
-            } else {
-                // Use the default foreground if no style is found
-                t = t.set(
-                    "fill",
-                    format!(
-                        "#{:02X}{:02X}{:02X}{:02X}", // Ensure that each RGB value converts accurately to a HEX
-                        fg.r,
-                        fg.g,
-                        fg.b,
-                        colors.background_alpha.to_u8()
-                    ),
-                );
-            }
+            // Use the default foreground if no style is found
+            let color = if unstyled { text_fg } else { style.foreground };
+
+            let color = match colors.contrast_boost {
+                Some(factor) => boost_contrast(color, bg, factor),
+                None => color,
+            };
+
+            // Colors too close to the background get pushed away from it, remembering each one's fix
+            let color = match colors.min_contrast {
+                Some(min) => *contrast_fixes
+                    .entry((color.r, color.g, color.b))
+                    .or_insert_with(|| ensure_contrast(color, bg, min))
+                    .as_ref()
+                    .unwrap_or(&color),
+                None => color,
+            };
 
-            use syntect::highlighting::FontStyle;
+            t = t.set(
+                "fill",
+                format!(
+                    "#{:02X}{:02X}{:02X}{:02X}", // Ensure that each RGB value converts accurately to a HEX
+                    color.r,
+                    color.g,
+                    color.b,
+                    text_alpha(unstyled, colors)
+                ),
+            );
 
             if style.font_style.contains(FontStyle::BOLD) {
-                t = t.set("font-weight", "bold");
+                // Relative, so bold still stands out against a custom base weight
+                t = t.set("font-weight", "bolder");
             }
 
             if style.font_style.contains(FontStyle::ITALIC) {
                 t = t.set("font-style", "italic");
             }
 
+            if style.background != theme_bg {
+                let c = style.background;
+                let scale = line_scale(i);
+                token_backgrounds.push((
+                    i,
+                    Rectangle::new()
+                        .set("x", advance(&segments) * scale)
+                        .set("y", baseline(i) - ascent * scale)
+                        .set(
+                            "width",
+                            advance(segment.trim_end_matches(['\r', '\n'])) * scale,
+                        )
+                        .set("height", (ascent - descent) * scale)
+                        .set(
+                            "fill",
+                            format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+                        ),
+                ));
+            }
+
             text = text.add(t);
             segments.push_str(segment);
         }
 
+        // Ticks go where each tab ended up, found by replaying the expansion on the original line
+        if layout.tab_stops {
+            let tab_width = layout.tab_width;
+            let mut column = 0;
+            for c in line.iter().flat_map(|(_, segment)| segment.chars()) {
+                if c == '\t' {
+                    column += tab_width - column % tab_width;
+                    let scale = line_scale(i);
+                    let x = column as f32 * column_width * scale;
+                    let baseline = baseline(i);
+                    tab_markers.push((
+                        i,
+                        Line::new()
+                            .set("x1", x)
+                            .set("y1", baseline - ascent * scale)
+                            .set("x2", x)
+                            .set("y2", baseline - descent * scale)
+                            .set(
+                                "stroke",
+                                accents.guide.clone().unwrap_or_else(|| {
+                                    format!("#{:02X}{:02X}{:02X}33", fg.r, fg.g, fg.b)
+                                }),
+                            )
+                            .set("stroke-width", 1),
+                    ));
+                } else if !is_zero_width(c) {
+                    column += 1;
+                }
+            }
+        }
+
+        // Placed right after the code, since the newline itself would otherwise push it along a space
+        // Read off the source, as the highlighted segments may have had their endings cut off
+        if layout.show_line_endings {
+            let body = segments.trim_end_matches(['\r', '\n']);
+            let marker = match line_endings.get(i).copied() {
+                _ if body.is_empty() => None,
+                Some("\r\n") => Some("↵"),
+                Some("\n") => Some("⏎"),
+                _ => None,
+            };
+
+            if let Some(marker) = marker {
+                let x = advance(body) * line_scale(i);
+                text = text.add(
+                    TSpan::new(marker)
+                        .set("x", x)
+                        .set("fill", fg_hex.clone())
+                        .set("opacity", 0.4),
+                );
+            }
+        }
+
         // Calculate the width for this line
-        let width: f32 = segments
-            .chars()
-            .map(|c| font.glyphs.metrics(c, font.size).advance_width)
-            .sum();
-        max_width = max_width.max(width as u32);
+        // Combining and invisible characters attach to their neighbours rather than taking a column
+        let width = advance(&segments) * line_scale(i);
+
+        // Lines past the limit are cut off there and faded out rather than widening the canvas
+        let limit = layout
+            .max_width_chars
+            .map(|chars| chars as f32 * column_width * line_scale(i));
+        let width = match limit {
+            Some(limit) if width > limit => {
+                truncated.push(i);
+                limit
+            }
+            _ => width,
+        };
+        max_width = max_width.max(width.ceil() as u32);
+        line_widths.push(width);
+        line_texts.push(text);
+    }
+
+    // Aligned lines shift right by their share of the room the widest line leaves them,
+    // and everything drawn along a line shifts with it
+    let line_offsets: Vec<f32> = line_widths
+        .iter()
+        .map(|&width| match layout.text_align {
+            TextAlign::Left => 0.0,
+            TextAlign::Center => (max_width as f32 - width) / 2.0,
+            TextAlign::Right => max_width as f32 - width,
+        })
+        .collect();
+    let offset = |i: usize| line_offsets.get(i).copied().unwrap_or(0.0);
+    for (i, text) in line_texts.into_iter().enumerate() {
+        g = g.add(shift_x(text, offset(i)));
+    }
 
-        g = g.add(text);
+    let adjusted = contrast_fixes.values().filter(|fix| fix.is_some()).count();
+    if adjusted > 0 {
+        info!(
+            "Adjusted {} color{} to reach a {}:1 contrast ratio",
+            adjusted,
+            if adjusted == 1 { "" } else { "s" },
+            colors.min_contrast.unwrap_or_default()
+        );
     }
 
-    let height = get_canvas_height(lines.len(), font);
+    let mut height = get_canvas_height(lines.len(), font);
+    // The first line takes its usual share of the height and then some
+    if let (Some(factor), false) = (layout.emphasize_first_line, lines.is_empty()) {
+        height += get_canvas_height(1, font) * (factor - 1.0);
+    }
+
+    // Glow hugs the glyphs themselves, so it's applied to the text group rather than the canvas
+    let glow = colors
+        .text_glow
+        .map(|c| format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a));
+    if glow.is_some() {
+        g = g.set("filter", "url(#text-glow)");
+    }
+
+    // A fixed size overrides the natural one, so settle how oversized content is handled
+    // A column count pins the width the same way, but still measured in the font's own advance
+    let natural_width = match layout.columns {
+        Some(columns) => {
+            (columns as f32 * font.glyphs.metrics('0', font.size).advance_width).ceil() as u32
+        }
+        // The line ending markers aren't code, but still need a column to land in
+        None if layout.show_line_endings => max_width + column_width.ceil() as u32,
+        None => max_width,
+    };
+
+    // Notes get a column of their own to the right of the code, a couple of columns clear of it
+    let note_x = natural_width as f32 + 2.0 * column_width;
+    let natural_width = match layout
+        .annotations
+        .iter()
+        .map(|a| advance(&a.note))
+        .reduce(f32::max)
+    {
+        Some(widest) => (note_x + widest + column_width).ceil() as u32,
+        None => natural_width,
+    };
+    // Fitting into --size through the viewBox lays the content out at its natural size first
+    let size = match layout.preserve_aspect {
+        Some(_) => None,
+        None => layout.size,
+    };
+    let (canvas_width, canvas_height) = match size {
+        Some(size) => (size.width as f32, size.height as f32),
+        None => (
+            natural_width.max(layout.min_width.unwrap_or(0)) as f32,
+            height.max(layout.min_height.unwrap_or(0) as f32),
+        ),
+    };
+    let overflows = max_width as f32 > canvas_width || height > canvas_height;
+
+    // Pad the shorter side out to the requested ratio, leaving the content at its natural scale
+    let (doc_width, doc_height) = match layout.aspect {
+        Some(AspectRatio(ratio)) if canvas_width / canvas_height < ratio => {
+            (canvas_height * ratio, canvas_height)
+        }
+        Some(AspectRatio(ratio)) => (canvas_width, canvas_width / ratio),
+        None => (canvas_width, canvas_height),
+    };
+
+    // The margin surrounds the whole card, background included
+    let margin = layout.outer_margin;
+    // Window chrome is a title bar across the top of the card, pushing everything else down
+    let bar = match layout.chrome {
+        Chrome::None => 0.0,
+        _ => (font.size * 1.6).round(),
+    };
+    // The file's path comes next as a breadcrumb, in the code's own size
+    // Literal and piped input have no path to show, so they simply go without
+    let crumbs = match (layout.path_header, &source.file_handler) {
+        (true, Some(path)) => path_components(path, layout.path_root.as_deref()),
+        _ => Vec::new(),
+    };
+    let breadcrumb = match crumbs.is_empty() {
+        true => 0.0,
+        false => (font.size * 1.6).round(),
+    };
+    // A heading sits between any title bar and the code, in bigger text than the code
+    let heading_size = (font.size * 1.5).round();
+    let heading = match layout.title {
+        Some(_) => (heading_size * 1.6).round(),
+        None => 0.0,
+    };
+    let top = bar + breadcrumb + heading;
+    // Line numbers get a gutter of their own down the left, widening the card
+    let gutter = if layout.line_numbers {
+        let widest = line_numbers.iter().flatten().max().copied().unwrap_or(1);
+        let digits = widest.to_string().len();
+        (digits + 2) as f32 * column_width
+    } else {
+        0.0
+    };
+    // A title longer than the code widens the card to fit
+    let title_width = layout
+        .title
+        .as_deref()
+        .map_or(0.0, |title| advance(title) * heading_size / font.size);
+    let crumbs_width = advance(&crumbs.join(BREADCRUMB_SEPARATOR));
+    let card_width = (doc_width + gutter)
+        .max((gutter + title_width).ceil())
+        .max((gutter + crumbs_width).ceil());
+    // Padding insets everything below the title bar from the card's edges
+    let padding = layout.padding;
+    let (frame_width, frame_height) =
+        (card_width + 2.0 * padding, doc_height + top + 2.0 * padding);
+
+    // A shadow spreads about three deviations past the card, plus its offset, on top of what the margin leaves
+    let (shadow_left, shadow_top, shadow_right, shadow_bottom) = match layout.shadow {
+        Some(ShadowOffset { x, y }) => {
+            let spread = layout.shadow_blur * 3.0;
+            let room = |side: f32| (side - margin).max(0.0).ceil();
+            (
+                room(spread - x),
+                room(spread - y),
+                room(spread + x),
+                room(spread + y),
+            )
+        }
+        None => (0.0, 0.0, 0.0, 0.0),
+    };
+    let (page_width, page_height) = (
+        frame_width + 2.0 * margin + shadow_left + shadow_right,
+        frame_height + 2.0 * margin + shadow_top + shadow_bottom,
+    );
+
+    // The natural page is what the viewBox shows, stretched or letterboxed into the requested size
+    let (out_width, out_height) = match (&layout.preserve_aspect, layout.size) {
+        (Some(_), Some(size)) => (size.width as f32, size.height as f32),
+        _ => (page_width, page_height),
+    };
 
     // Build up the SVG document boilerplate
     let mut doc = Document::new()
         .set("xmlns", "http://www.w3.org/2000/svg")
-        .set("width", format!("{:.0}px", max_width))
-        .set("height", format!("{:.0}px", height));
+        // Rounded up, as rounding to nearest could shave a partial pixel off the last glyph or line
+        .set("width", format!("{}px", out_width.ceil()))
+        .set("height", format!("{}px", out_height.ceil()));
+
+    // Logical coordinates, so the document scales crisply instead of being pinned to its px size
+    if layout.viewbox || layout.preserve_aspect.is_some() {
+        doc = doc.set("viewBox", (0, 0, page_width, page_height));
+    }
+    if let Some(PreserveAspect(value)) = &layout.preserve_aspect {
+        doc = doc.set("preserveAspectRatio", value.as_str());
+    }
+
+    if overflows {
+        match layout.overflow {
+            // Wrapping already made every line fit the width, so only extra height is left to crop
+            Overflow::Clip | Overflow::Wrap => {
+                add_clip_path(
+                    &mut doc,
+                    "canvas-clip",
+                    0.0,
+                    0.0,
+                    canvas_width as f64,
+                    canvas_height as f64,
+                );
+                g = g.set("clip-path", "url(#canvas-clip)");
+            }
+            Overflow::Scale => {
+                // A zero-sized canvas or empty content has no ratio to scale by, so it's left as is
+                let ratio = |canvas: f32, content: f32| match canvas > 0.0 && content > 0.0 {
+                    true => canvas / content,
+                    false => 1.0,
+                };
+                let factor =
+                    ratio(canvas_width, max_width as f32).min(ratio(canvas_height, height));
+                // Past the floor the text stays legible and whatever's left over is cropped
+                if let Some(min_scale) = layout.min_scale.filter(|&min| factor < min) {
+                    add_clip_path(
+                        &mut doc,
+                        "canvas-clip",
+                        0.0,
+                        0.0,
+                        (canvas_width / min_scale) as f64,
+                        (canvas_height / min_scale) as f64,
+                    );
+                    g = g
+                        .set("clip-path", "url(#canvas-clip)")
+                        .set("transform", format!("scale({})", min_scale));
+                } else {
+                    g = g.set("transform", format!("scale({})", factor));
+                }
+            }
+            Overflow::Error => {
+                return Err(SiaError::InvalidConfig(format!(
+                    "content is {:.0}x{:.0} but the canvas is {:.0}x{:.0}, try --wrap or a larger --size/--columns",
+                    max_width, height, canvas_width, canvas_height
+                ))
+                .into());
+            }
+        }
+    }
+
+    // Its own group, since the text group may already carry the canvas clip
+    if let (false, Some(chars)) = (truncated.is_empty(), layout.max_width_chars) {
+        add_clip_path(
+            &mut doc,
+            "chars-clip",
+            0.0,
+            0.0,
+            (chars as f32 * column_width) as f64,
+            height as f64,
+        );
+        add_fade(
+            &mut doc,
+            "chars-fade",
+            &format!("#{:02X}{:02X}{:02X}", bg.r, bg.g, bg.b),
+            bg.a as f64 / 255.0,
+        );
+        g = Group::new().set("clip-path", "url(#chars-clip)").add(g);
+    }
+
+    if let Some(glow_hex) = glow {
+        add_glow(
+            &mut doc,
+            "text-glow",
+            &glow_hex,
+            layout.text_glow_blur as f64,
+        );
+    }
+
+    // Left transparent unless a color is given
+    if let (true, Some(c)) = (margin > 0.0, colors.outer) {
+        doc = doc.add(
+            Rectangle::new()
+                .set("width", "100%")
+                .set("height", "100%")
+                .set(
+                    "fill",
+                    format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+                ),
+        );
+    }
+
+    // The card: background and everything on it, inset by the margin and any room made for the shadow
+    let mut card = Group::new();
+    let (card_x, card_y) = (margin + shadow_left, margin + shadow_top);
+    if card_x > 0.0 || card_y > 0.0 {
+        card = card.set("transform", format!("translate({} {})", card_x, card_y));
+    }
+
+    if let Some(ShadowOffset { x, y }) = layout.shadow {
+        let spread = layout.shadow_blur * 3.0;
+        add_shadow(
+            &mut doc,
+            "card-shadow",
+            (x as f64, y as f64),
+            layout.shadow_blur as f64,
+            (
+                (x.min(0.0) - spread) as f64,
+                (y.min(0.0) - spread) as f64,
+                (frame_width + x.abs() + 2.0 * spread) as f64,
+                (frame_height + y.abs() + 2.0 * spread) as f64,
+            ),
+        );
+        card = card.set("filter", "url(#card-shadow)");
+    }
 
     // Draw background rect
-    let bg_rect = Rectangle::new()
-        .set("width", "100%")
-        .set("height", "100%")
+    let mut bg_rect = Rectangle::new()
+        .set("width", frame_width)
+        .set("height", frame_height)
         .set("fill", bg_hex.clone());
-    doc = doc.add(bg_rect);
+    // Rounded corners leave the page transparent past them, and what's layered over the
+    // whole card is clipped to match so its corners don't poke out
+    let frame_clip = layout.radius > 0.0;
+    if frame_clip {
+        bg_rect = add_corner_radius(bg_rect, layout.radius as f64);
+        doc.append(
+            Definitions::new().add(
+                ClipPath::new()
+                    .set("id", "card-clip")
+                    .add(add_corner_radius(
+                        Rectangle::new()
+                            .set("width", frame_width)
+                            .set("height", frame_height),
+                        layout.radius as f64,
+                    )),
+            ),
+        );
+    }
+    let framed = |elem: Rectangle| match frame_clip {
+        true => elem.set("clip-path", "url(#card-clip)"),
+        false => elem,
+    };
+    card = card.add(bg_rect);
 
-    doc = doc.add(g);
+    // The pattern is its own rect so the base color still shows between the marks
+    if let Some(kind) = layout.bg_pattern {
+        let pattern_hex = match colors.bg_pattern {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => format!("#{:02X}{:02X}{:02X}1A", fg.r, fg.g, fg.b),
+        };
+
+        add_pattern(
+            &mut doc,
+            "bg-pattern",
+            kind,
+            &pattern_hex,
+            layout.bg_pattern_spacing as f64,
+        );
+        card = card.add(framed(
+            Rectangle::new()
+                .set("y", bar)
+                .set("width", frame_width)
+                .set("height", frame_height - bar)
+                .set("fill", "url(#bg-pattern)"),
+        ));
+    }
+
+    // Over the background, but under the title bar and the code
+    if let Some(strength) = layout.vignette {
+        add_vignette(&mut doc, "vignette", strength.0 as f64);
+        card = card.add(framed(
+            Rectangle::new()
+                .set("width", frame_width)
+                .set("height", frame_height)
+                .set("fill", "url(#vignette)"),
+        ));
+    }
+
+    if bar > 0.0 {
+        let bar_hex = accents
+            .line_highlight
+            .clone()
+            .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}14", fg.r, fg.g, fg.b));
+        card = card.add(framed(
+            Rectangle::new()
+                .set("width", frame_width)
+                .set("height", bar)
+                .set("fill", bar_hex),
+        ));
+        card = add_window_controls(card, layout.chrome, frame_width, bar, fg);
+
+        // Centered on the bar's middle, rather than sitting on a baseline like the heading below
+        if let Some(title) = &layout.window_title {
+            let mut text = Text::new(space_text(title, layout.space_mode))
+                .set("x", frame_width / 2.0)
+                .set("y", bar / 2.0 + (ascent + descent) / 2.0)
+                .set("text-anchor", "middle")
+                .set("font-family", font_family(font))
+                .set("font-size", font.size)
+                .set("fill", fg_hex.clone())
+                .set("opacity", 0.7);
+            if let SpaceMode::Preserve = layout.space_mode {
+                text = text.set("xml:space", "preserve");
+            }
+            if let Some(weight) = font.weight {
+                text = text.set("font-weight", weight);
+            }
+            card = card.add(text);
+        }
+    }
+
+    // Directories fade back behind the file itself, with the separators fainter still
+    if let Some((file, dirs)) = crumbs.split_last() {
+        let separator_hex = accents
+            .guide
+            .clone()
+            .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}66", fg.r, fg.g, fg.b));
+        let mut text = Text::new("")
+            .set("x", padding + gutter)
+            .set("y", bar + padding + font.size * 1.2)
+            .set("font-family", font_family(font))
+            .set("font-size", font.size)
+            .set("fill", fg_hex.clone());
+        if let SpaceMode::Preserve = layout.space_mode {
+            text = text.set("xml:space", "preserve");
+        }
+        if let Some(weight) = font.weight {
+            text = text.set("font-weight", weight);
+        }
+        for dir in dirs {
+            text = text
+                .add(TSpan::new(space_text(dir, layout.space_mode)).set("opacity", 0.6))
+                .add(
+                    TSpan::new(space_text(BREADCRUMB_SEPARATOR, layout.space_mode))
+                        .set("fill", separator_hex.clone()),
+                );
+        }
+        text = text.add(TSpan::new(space_text(file, layout.space_mode)));
+        card = card.add(text);
+    }
+
+    if let Some(title) = &layout.title {
+        let (x, anchor) = match layout.title_align {
+            TextAlign::Left => (padding + gutter, "start"),
+            TextAlign::Center => (frame_width / 2.0, "middle"),
+            TextAlign::Right => (padding + card_width, "end"),
+        };
+        let mut text = Text::new(space_text(title, layout.space_mode))
+            .set("x", x)
+            .set("y", bar + padding + breadcrumb + heading_size * 1.2)
+            .set("text-anchor", anchor)
+            .set("font-family", font_family(font))
+            .set("font-size", heading_size)
+            .set("font-weight", "bold")
+            .set("fill", fg_hex.clone());
+        if let SpaceMode::Preserve = layout.space_mode {
+            text = text.set("xml:space", "preserve");
+        }
+        card = card.add(text);
+    }
+
+    // Everything drawn over the background, centered within any aspect padding
+    // and nudged down to the middle of a taller fixed or minimum-height canvas when asked
+    let v_offset = if layout.vcenter && height < canvas_height {
+        (canvas_height - height) / 2.0
+    } else {
+        0.0
+    };
+    let content_x = (doc_width - canvas_width) / 2.0 + gutter + padding;
+    let content_y = (doc_height - canvas_height) / 2.0 + v_offset + top + padding;
+    let mut content = Group::new();
+    if content_x > 0.0 || content_y > 0.0 {
+        content = content.set(
+            "transform",
+            format!("translate({} {})", content_x, content_y),
+        );
+    }
+
+    // Numbers sit right-aligned against the code, level with its lines however the content moved
+    if layout.line_numbers {
+        let number_hex = accents
+            .gutter_foreground
+            .clone()
+            .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}66", fg.r, fg.g, fg.b));
+        let current = layout.current_line;
+        if let Some(line) = current.filter(|&line| !line_numbers.contains(&Some(line))) {
+            return Err(SiaError::InvalidConfig(format!(
+                "can't make line {} current, it isn't shown",
+                line
+            ))
+            .into());
+        }
+
+        let mut numbers = Group::new()
+            .set("font-family", font_family(font))
+            .set("font-size", font.size)
+            .set("text-anchor", "end")
+            .set("fill", number_hex);
+        if let Some(weight) = font.weight {
+            numbers = numbers.set("font-weight", weight);
+        }
+
+        for (i, &n) in line_numbers.iter().enumerate() {
+            let Some(n) = n else {
+                continue;
+            };
+            // Relative numbers count the distance from the current line, which keeps its own
+            let label = match current {
+                Some(current) if layout.relative_line_numbers && n != current => {
+                    n.abs_diff(current)
+                }
+                _ => n,
+            };
+            let mut number = Text::new(label.to_string())
+                .set("x", padding + gutter - column_width)
+                .set("y", content_y + baseline(i));
+            if current == Some(n) {
+                number = number.set("fill", fg_hex.clone());
+            }
+            numbers = numbers.add(number);
+        }
+        card = card.add(numbers);
+
+        // Halfway between the numbers and the code, the full height of the card below any title bar or heading
+        if layout.gutter_separator {
+            let x = padding + gutter - column_width / 2.0;
+            card = card.add(
+                Line::new()
+                    .set("x1", x)
+                    .set("y1", top + padding)
+                    .set("x2", x)
+                    .set("y2", top + padding + doc_height)
+                    .set(
+                        "stroke",
+                        accents
+                            .guide
+                            .clone()
+                            .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}33", fg.r, fg.g, fg.b)),
+                    )
+                    .set("stroke-width", 1),
+            );
+        }
+    }
+
+    // Stripes go down first so both the rulers and the text land on top of them
+    if layout.zebra && !lines.is_empty() {
+        let zebra_hex = match colors.zebra {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => accents
+                .line_highlight
+                .clone()
+                .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}0D", fg.r, fg.g, fg.b)),
+        };
+        // Measured off the canvas height, counting the emphasized line's share of it
+        let stripe = height / (lines.len() as f32 - 1.0 + emphasis);
+
+        for i in (1..lines.len()).step_by(2) {
+            content = content.add(
+                Rectangle::new()
+                    .set("x", 0)
+                    .set("y", (i as f32 - 1.0 + emphasis) * stripe)
+                    .set("width", canvas_width)
+                    .set("height", stripe)
+                    .set("fill", zebra_hex.clone()),
+            );
+        }
+    }
+
+    if let Some(added) = &layout.diff_added {
+        let added_hex = match colors.diff_added {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => "#A3BE8C40".to_string(),
+        };
+        for &i in added.iter().filter(|&&i| i < lines.len()) {
+            content = content.add(
+                Rectangle::new()
+                    .set("x", 0)
+                    .set("y", line_top(i))
+                    .set("width", canvas_width)
+                    .set("height", line_height(i))
+                    .set("fill", added_hex.clone()),
+            );
+        }
+    }
+
+    if !layout.highlight_lines.is_empty() {
+        let highlight_hex = accents
+            .line_highlight
+            .clone()
+            .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}26", fg.r, fg.g, fg.b));
+        for i in (0..lines.len()).filter(|&i| highlighted(i)) {
+            content = content.add(
+                Rectangle::new()
+                    .set("x", 0)
+                    .set("y", line_top(i))
+                    .set("width", canvas_width)
+                    .set("height", line_height(i))
+                    .set("fill", highlight_hex.clone()),
+            );
+        }
+    }
+
+    // Rulers sit between the background and the text so glyphs stay on top
+    if !layout.rulers.is_empty() {
+        let ruler_hex = match colors.ruler {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => accents
+                .guide
+                .clone()
+                .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}33", fg.r, fg.g, fg.b)),
+        };
+
+        for &column in &layout.rulers {
+            let x = column as f32 * column_width;
+            content = content.add(
+                Line::new()
+                    .set("x1", x)
+                    .set("y1", -v_offset)
+                    .set("x2", x)
+                    .set("y2", canvas_height - v_offset)
+                    .set("stroke", ruler_hex.clone())
+                    .set("stroke-width", 1),
+            );
+        }
+    }
+
+    // Keyword badges also go behind the glyphs
+    if !layout.keywords.is_empty() {
+        let badge_hex = match colors.keyword {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => accents
+                .find_highlight
+                .clone()
+                .unwrap_or_else(|| "#EBCB8B59".to_string()),
+        };
+        let comments = comment_ranges(&source_lines, syntax, ss);
+
+        for (i, &line) in source_lines.iter().enumerate() {
+            let baseline = baseline(i);
+            let scale = line_scale(i);
+
+            for keyword in &layout.keywords {
+                for (start, _) in line.match_indices(keyword.as_str()) {
+                    let end = start + keyword.len();
+
+                    // Whole words only, so TODOS or MYTODO don't light up
+                    let is_word =
+                        |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+                    if is_word(line[..start].chars().next_back())
+                        || is_word(line[end..].chars().next())
+                    {
+                        continue;
+                    }
+
+                    if !comments[i].iter().any(|range| range.contains(&start)) {
+                        continue;
+                    }
+
+                    let mut column = 0;
+                    expand_tabs(&line[..start], layout.tab_width, &mut column);
+                    let badge = Rectangle::new()
+                        .set("x", column as f32 * column_width * scale + offset(i))
+                        .set("y", baseline - ascent * scale)
+                        .set(
+                            "width",
+                            keyword.chars().count() as f32 * column_width * scale,
+                        )
+                        .set("height", (ascent - descent) * scale)
+                        .set("fill", badge_hex.clone());
+                    content =
+                        content.add(add_corner_radius(badge, (font.size * scale / 6.0) as f64));
+                }
+            }
+        }
+    }
+
+    // The selection follows the code line by line, running a column past the end of any it wraps over
+    if let Some(ByteRange { start, end }) = layout.selection {
+        let contents = &source.contents;
+        if end > contents.len()
+            || !contents.is_char_boundary(start)
+            || !contents.is_char_boundary(end)
+        {
+            return Err(SiaError::InvalidConfig(format!(
+                "selection {}..{} doesn't fall on characters within the {} byte input",
+                start,
+                end,
+                contents.len()
+            ))
+            .into());
+        }
+
+        let selection_hex = match colors.selection {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => accents
+                .selection
+                .clone()
+                .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}33", fg.r, fg.g, fg.b)),
+        };
+        let tab_width = layout.tab_width;
+
+        let mut line_start = 0;
+        for (i, line) in LinesWithEndings::from(contents).enumerate() {
+            let line_end = line_start + line.len();
+            if line_start >= end {
+                break;
+            }
+
+            let body = line.trim_end_matches(['\r', '\n']);
+            let (from, to) = (
+                start.max(line_start) - line_start,
+                end.min(line_end) - line_start,
+            );
+            if from < to {
+                let mut first = 0;
+                expand_tabs(&body[..from.min(body.len())], tab_width, &mut first);
+                let mut last = first;
+                expand_tabs(
+                    &body[from.min(body.len())..to.min(body.len())],
+                    tab_width,
+                    &mut last,
+                );
+                if to > body.len() {
+                    last += 1;
+                }
+
+                let scale = line_scale(i);
+                content = content.add(
+                    Rectangle::new()
+                        .set("x", first as f32 * column_width * scale + offset(i))
+                        .set("y", line_top(i))
+                        .set("width", (last - first) as f32 * column_width * scale)
+                        .set("height", line_height(i))
+                        .set("fill", selection_hex.clone()),
+                );
+            }
+            line_start = line_end;
+        }
+    }
+
+    for (i, rect) in token_backgrounds {
+        content = content.add(shift_x(rect, offset(i)));
+    }
+    for (i, marker) in tab_markers {
+        content = content.add(shift_x(marker, offset(i)));
+    }
+
+    // Dots for spaces and arrows for tabs, faint enough that the code still reads first
+    if let Some(mode) = layout.show_whitespace {
+        let marker_hex = accents
+            .guide
+            .clone()
+            .unwrap_or_else(|| format!("#{:02X}{:02X}{:02X}4D", fg.r, fg.g, fg.b));
+        let tab_width = layout.tab_width;
+
+        for (i, line) in source_lines.iter().enumerate() {
+            let scale = line_scale(i);
+            let middle = baseline(i) - ascent * scale / 3.0;
+            let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+
+            let mut column = 0;
+            for (at, c) in line.char_indices() {
+                let start = column;
+                column += match c {
+                    '\t' => tab_width - column % tab_width,
+                    c if is_zero_width(c) => 0,
+                    _ => 1,
+                };
+
+                if matches!(mode, ShowWhitespace::Indent) && at >= indent {
+                    break;
+                }
+
+                let (x1, x2) = (
+                    start as f32 * column_width * scale + offset(i),
+                    column as f32 * column_width * scale + offset(i),
+                );
+                match c {
+                    ' ' => {
+                        content = content.add(
+                            Circle::new()
+                                .set("cx", (x1 + x2) / 2.0)
+                                .set("cy", middle)
+                                .set("r", (font.size / 16.0).max(1.0))
+                                .set("fill", marker_hex.clone()),
+                        );
+                    }
+                    '\t' => {
+                        let head = column_width / 3.0;
+                        let end = x2 - column_width / 4.0;
+                        content = content.add(
+                            Path::new()
+                                .set(
+                                    "d",
+                                    format!(
+                                        "M {} {} H {} M {} {} L {} {} L {} {}",
+                                        x1 + column_width / 4.0,
+                                        middle,
+                                        end,
+                                        end - head,
+                                        middle - head,
+                                        end,
+                                        middle,
+                                        end - head,
+                                        middle + head
+                                    ),
+                                )
+                                .set("fill", "none")
+                                .set("stroke", marker_hex.clone())
+                                .set("stroke-width", 1),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    content = content.add(g);
+
+    // Like an editor's scroll shadows, darkening the edges the canvas cuts content off at
+    // Content always starts at the top, so only the right and bottom edges can be cut
+    if layout.scroll_shadows && overflows && matches!(layout.overflow, Overflow::Clip) {
+        let blur = font.size * 0.5;
+        // Straddling the edge, so the darkest part of the falloff lands inside the canvas
+        let depth = blur * 3.0;
+        // Light themes only need a hint of shadow, dark ones need more to show at all
+        let opacity = if relative_luminance(bg) < 0.5 {
+            0.6
+        } else {
+            0.25
+        };
+        add_blur(&mut doc, "scroll-shadow", blur as f64);
+
+        let edge = |x: f32, y: f32, width: f32, height: f32| {
+            Rectangle::new()
+                .set("x", x)
+                .set("y", y)
+                .set("width", width)
+                .set("height", height)
+                .set("fill", "#000000")
+                .set("fill-opacity", opacity)
+                .set("filter", "url(#scroll-shadow)")
+        };
+        let mut shadows = Group::new().set("clip-path", "url(#canvas-clip)");
+        if max_width as f32 > canvas_width {
+            shadows = shadows.add(edge(canvas_width - blur, 0.0, depth, canvas_height));
+        }
+        if height > canvas_height {
+            shadows = shadows.add(edge(0.0, canvas_height - blur, canvas_width, depth));
+        }
+        content = content.add(shadows);
+    }
+
+    // Notes sit on their line's baseline, joined to the end of its code by a dashed connector
+    if !layout.annotations.is_empty() {
+        let note_hex = match colors.annotation {
+            Some(c) => format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a),
+            None => format!("#{:02X}{:02X}{:02X}99", fg.r, fg.g, fg.b),
+        };
+
+        for annotation in &layout.annotations {
+            // Numbered like the gutter, and level with the row a wrapped line ends on
+            let row = row_lines.iter().rposition(|&n| n == Some(annotation.line));
+            let Some((i, &width)) = row.and_then(|i| Some((i, line_widths.get(i)?))) else {
+                return Err(SiaError::InvalidConfig(format!(
+                    "can't annotate line {}, it isn't shown",
+                    annotation.line
+                ))
+                .into());
+            };
+            let baseline = baseline(i);
+            let middle = baseline - (ascent + descent) * line_scale(i) / 2.0;
+
+            content = content.add(
+                Line::new()
+                    .set("x1", offset(i) + width + column_width / 2.0)
+                    .set("y1", middle)
+                    .set("x2", note_x - column_width / 2.0)
+                    .set("y2", middle)
+                    .set("stroke", note_hex.clone())
+                    .set("stroke-width", 1)
+                    .set("stroke-dasharray", "2 2"),
+            );
+            let mut note = Text::new(space_text(&annotation.note, layout.space_mode))
+                .set("x", note_x)
+                .set("y", baseline)
+                .set("font-family", font_family(font))
+                .set("font-size", font.size)
+                .set("font-style", "italic")
+                .set("fill", note_hex.clone());
+            if let SpaceMode::Preserve = layout.space_mode {
+                note = note.set("xml:space", "preserve");
+            }
+            if let Some(weight) = font.weight {
+                note = note.set("font-weight", weight);
+            }
+            content = content.add(note);
+        }
+    }
+
+    // The fade sits over the last few columns of each cut-off line
+    if let Some(chars) = layout.max_width_chars {
+        let fade_width = column_width * FADE_COLUMNS.min(chars) as f32;
+        for &i in &truncated {
+            let scale = line_scale(i);
+            content = content.add(
+                Rectangle::new()
+                    .set("x", (chars as f32 * column_width - fade_width) * scale)
+                    .set("y", line_top(i))
+                    .set("width", fade_width * scale)
+                    .set("height", line_height(i))
+                    .set("fill", "url(#chars-fade)"),
+            );
+        }
+    }
+
+    // Developer aid: where each line's baseline lands, and the bounds it's all meant to fit
+    if layout.debug_grid {
+        for i in 0..lines.len() {
+            let baseline = baseline(i);
+            content = content.add(
+                Line::new()
+                    .set("x1", 0)
+                    .set("y1", baseline)
+                    .set("x2", canvas_width)
+                    .set("y2", baseline)
+                    .set("stroke", "#FF00FF")
+                    .set("stroke-width", 1),
+            );
+        }
+        content = content.add(
+            Rectangle::new()
+                .set("y", -v_offset)
+                .set("width", canvas_width)
+                .set("height", canvas_height)
+                .set("fill", "none")
+                .set("stroke", "#00FFFF")
+                .set("stroke-width", 1),
+        );
+    }
+
+    // Designer aid: baselines and the text's bounding box, faint enough to see the code through
+    if layout.guides {
+        for i in 0..lines.len() {
+            let baseline = baseline(i);
+            content = content.add(
+                Line::new()
+                    .set("x1", 0)
+                    .set("y1", baseline)
+                    .set("x2", max_width)
+                    .set("y2", baseline)
+                    .set("stroke", GUIDE_COLOR)
+                    .set("stroke-opacity", 0.35)
+                    .set("stroke-dasharray", "4 3")
+                    .set("stroke-width", 1),
+            );
+        }
+        content = content.add(
+            Rectangle::new()
+                .set("width", max_width)
+                .set("height", height)
+                .set("fill", GUIDE_COLOR)
+                .set("fill-opacity", 0.06)
+                .set("stroke", GUIDE_COLOR)
+                .set("stroke-opacity", 0.8)
+                .set("stroke-width", 1),
+        );
+    }
+
+    card = card.add(content);
+    doc = doc.add(card);
+
+    // The image's size, tagged in the corner where it's least likely to cover code
+    if layout.guides {
+        let label = format!("{} × {} px", out_width.ceil(), out_height.ceil());
+        let size = (font.size * 0.6).max(10.0);
+        let (width, height) = (label.chars().count() as f32 * size * 0.6 + size, size * 1.6);
+
+        let mut text = Text::new(label)
+            .set("x", width / 2.0)
+            .set("y", height / 2.0)
+            .set("dominant-baseline", "central")
+            .set("text-anchor", "middle")
+            .set("font-family", font_family(font))
+            .set("font-size", size)
+            .set("fill", "#FFFFFF");
+        if let Some(weight) = font.weight {
+            text = text.set("font-weight", weight);
+        }
+
+        doc = doc.add(
+            Group::new()
+                .set(
+                    "transform",
+                    format!("translate({} {})", out_width - width, out_height - height),
+                )
+                .add(
+                    Rectangle::new()
+                        .set("width", width)
+                        .set("height", height)
+                        .set("rx", size * 0.3)
+                        .set("fill", GUIDE_COLOR)
+                        .set("fill-opacity", 0.85),
+                )
+                .add(text),
+        );
+    }
 
     Ok(doc)
 }
 
-fn add_shadow(elem: Document, id: &str, x_offset: f64, y_offset: f64, blur: f64) -> Document {
+/// Lays rendered documents out in a grid `per_row` wide, each under its label, with dividers between.
+/// Each one's ids are prefixed so their clip paths and gradients can't collide.
+pub(crate) fn sheet_svg(
+    panels: Vec<(Document, String)>,
+    per_row: usize,
+    theme: &Theme,
+    font: &FontConfig,
+) -> Document {
+    let label_height = (font.size * 1.6).round();
+    let gap = font.size.round();
+    let per_row = per_row.max(1);
+
+    let sizes: Vec<(f32, f32)> = panels
+        .iter()
+        .map(|(panel, _)| {
+            let (width, height) = get_dimensions(panel);
+            (width as f32, height as f32 + label_height)
+        })
+        .collect();
+
+    // Every cell in a column shares its widest panel's width, and every cell in a row its tallest
+    let mut column_widths = vec![0.0f32; per_row.min(sizes.len())];
+    let mut row_heights = vec![0.0f32; sizes.len().div_ceil(per_row)];
+    for (i, &(w, h)) in sizes.iter().enumerate() {
+        column_widths[i % per_row] = column_widths[i % per_row].max(w);
+        row_heights[i / per_row] = row_heights[i / per_row].max(h);
+    }
+    let starts = |lengths: &[f32]| {
+        lengths
+            .iter()
+            .scan(0.0, |start, length| {
+                let this = *start;
+                *start += length + gap;
+                Some(this)
+            })
+            .collect::<Vec<f32>>()
+    };
+    let (xs, ys) = (starts(&column_widths), starts(&row_heights));
+    let width = column_widths.iter().map(|w| w + gap).sum::<f32>() - gap;
+    let height = row_heights.iter().map(|h| h + gap).sum::<f32>() - gap;
+
+    let bg = theme.settings.background.unwrap_or(Color::BLACK);
+    let fg = theme.settings.foreground.unwrap_or(Color::WHITE);
+    let fg_hex = format!("#{:02X}{:02X}{:02X}", fg.r, fg.g, fg.b);
+    let divider_hex = format!("#{:02X}{:02X}{:02X}33", fg.r, fg.g, fg.b);
+
+    let mut doc = Document::new()
+        .set("xmlns", "http://www.w3.org/2000/svg")
+        .set("width", format!("{}px", width.ceil()))
+        .set("height", format!("{}px", height.ceil()))
+        .add(
+            Rectangle::new()
+                .set("width", "100%")
+                .set("height", "100%")
+                .set(
+                    "fill",
+                    format!("#{:02X}{:02X}{:02X}{:02X}", bg.r, bg.g, bg.b, bg.a),
+                ),
+        );
+
+    // Each divider sits in the middle of the gap before its column or row
+    let divider = |x1: f32, y1: f32, x2: f32, y2: f32| {
+        Line::new()
+            .set("x1", x1)
+            .set("y1", y1)
+            .set("x2", x2)
+            .set("y2", y2)
+            .set("stroke", divider_hex.clone())
+            .set("stroke-width", 1)
+    };
+    for &x in xs.iter().skip(1) {
+        doc = doc.add(divider(x - gap / 2.0, 0.0, x - gap / 2.0, height));
+    }
+    for &y in ys.iter().skip(1) {
+        doc = doc.add(divider(0.0, y - gap / 2.0, width, y - gap / 2.0));
+    }
+
+    let mut names = Group::new()
+        .set("font-family", font_family(font))
+        .set("font-size", font.size)
+        .set("fill", fg_hex)
+        .set("opacity", 0.7)
+        .set("xml:space", "preserve");
+    if let Some(weight) = font.weight {
+        names = names.set("font-weight", weight);
+    }
+
+    for (i, (panel, label)) in panels.into_iter().enumerate() {
+        let (x, y) = (xs[i % per_row], ys[i / per_row]);
+        names = names.add(
+            Text::new(label)
+                .set("x", x + font.size / 2.0)
+                .set("y", y + label_height * 0.7),
+        );
+        doc = doc.add(nest(panel, &format!("panel{}", i), x, y + label_height));
+    }
+
+    doc.add(names)
+}
+
+/// Serializes a document one element per line, except within each `<text>`: the svg crate breaks
+/// lines between a line's tspans too, and under `xml:space="preserve"` those render as spaces.
+pub(crate) fn serialize(doc: &Document) -> String {
+    let pretty = doc.to_string();
+    let mut out = String::with_capacity(pretty.len());
+    let mut rest = pretty.as_str();
+    while let Some(start) = rest.find("<text") {
+        let (before, text) = rest.split_at(start);
+        out.push_str(before);
+        let end = text
+            .find("</text>")
+            .map_or(text.len(), |i| i + "</text>".len());
+        out.extend(text[..end].split('\n'));
+        rest = &text[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Places a document as a nested `<svg>` at (x, y), with its ids prefixed.
+fn nest(mut doc: Document, prefix: &str, x: f32, y: f32) -> Document {
+    prefix_ids(&mut doc, prefix);
+    doc.set("x", x).set("y", y)
+}
+
+/// Prefixes the ids in `node` and everything under it, along with the `url(#...)` references to
+/// them. Only attribute values are rewritten, so code that happens to contain either is left alone.
+fn prefix_ids(node: &mut dyn Node, prefix: &str) {
+    if let Some(attributes) = node.get_attributes_mut() {
+        for (name, value) in attributes.iter_mut() {
+            let prefixed = match name.as_str() {
+                "id" => format!("{}-{}", prefix, &**value),
+                _ => value.replace("url(#", &format!("url(#{}-", prefix)),
+            };
+            *value = prefixed.into();
+        }
+    }
+    if let Some(children) = node.get_children_mut() {
+        for child in children {
+            prefix_ids(child.as_mut(), prefix);
+        }
+    }
+}
+
+/// Draws the buttons for a style of title bar `bar` px tall across a card `width` px wide.
+fn add_window_controls(mut card: Group, chrome: Chrome, width: f32, bar: f32, fg: Color) -> Group {
+    let middle = bar / 2.0;
+    match chrome {
+        // Traffic lights on the left
+        Chrome::Macos => {
+            for (i, fill) in ["#FF5F56", "#FFBD2E", "#27C93F"].iter().enumerate() {
+                card = card.add(
+                    Circle::new()
+                        .set("cx", bar * (0.6 + 0.6 * i as f32))
+                        .set("cy", middle)
+                        .set("r", bar * 0.2)
+                        .set("fill", *fill),
+                );
+            }
+        }
+        // Minimize, maximize and close outlines on the right
+        Chrome::Windows => {
+            let stroke = format!("#{:02X}{:02X}{:02X}B3", fg.r, fg.g, fg.b);
+            let half = bar * 0.15;
+            let close = width - bar * 0.75;
+            let maximize = close - bar * 1.25;
+            let minimize = maximize - bar * 1.25;
+
+            let path = format!(
+                "M {} {} H {} M {} {} L {} {} M {} {} L {} {}",
+                minimize - half,
+                middle,
+                minimize + half,
+                close - half,
+                middle - half,
+                close + half,
+                middle + half,
+                close + half,
+                middle - half,
+                close - half,
+                middle + half
+            );
+            card = card
+                .add(
+                    Path::new()
+                        .set("d", path)
+                        .set("fill", "none")
+                        .set("stroke", stroke.clone())
+                        .set("stroke-width", 1),
+                )
+                .add(
+                    Rectangle::new()
+                        .set("x", maximize - half)
+                        .set("y", middle - half)
+                        .set("width", 2.0 * half)
+                        .set("height", 2.0 * half)
+                        .set("fill", "none")
+                        .set("stroke", stroke)
+                        .set("stroke-width", 1),
+                );
+        }
+        Chrome::Plain | Chrome::None => {}
+    }
+    card
+}
+
+/// Decoration colors the theme defines for its editor, as hex, so generated extras match its look.
+/// Each is `None` when the theme leaves it out, and callers fall back to a tint of the foreground.
+struct ThemeAccents {
+    /// Current line background, used for zebra stripes
+    line_highlight: Option<String>,
+    /// Indent guide color, used for rulers and tab stops
+    guide: Option<String>,
+    /// Search match background, used for keyword badges
+    find_highlight: Option<String>,
+    /// Selected text background, used for --select
+    selection: Option<String>,
+    /// Line number color
+    gutter_foreground: Option<String>,
+}
+
+impl ThemeAccents {
+    fn from_theme(theme: &Theme) -> Self {
+        let hex =
+            |c: Option<Color>| c.map(|c| format!("#{:02X}{:02X}{:02X}{:02X}", c.r, c.g, c.b, c.a));
+        let settings = &theme.settings;
+
+        ThemeAccents {
+            line_highlight: hex(settings.line_highlight),
+            guide: hex(settings.guide),
+            find_highlight: hex(settings.find_highlight),
+            selection: hex(settings.selection),
+            gutter_foreground: hex(settings.gutter_foreground),
+        }
+    }
+}
+
+/// The canvas color: an alpha spelled out in --bg-color wins, otherwise --bg-alpha scales the base color's own alpha.
+pub(crate) fn background(theme_bg: Color, colors: &Colors) -> Color {
+    match colors.background {
+        Some(HexColor {
+            rgba,
+            explicit_alpha: true,
+        }) => Color {
+            r: rgba.r,
+            g: rgba.g,
+            b: rgba.b,
+            a: rgba.a,
+        },
+        Some(HexColor { rgba, .. }) => Color {
+            r: rgba.r,
+            g: rgba.g,
+            b: rgba.b,
+            a: colors.background_alpha.to_u8(),
+        },
+        None => Color {
+            a: (theme_bg.a as f32 * colors.background_alpha.0).round() as u8,
+            ..theme_bg
+        },
+    }
+}
+
+/// The color of unstyled text, which can be recolored on its own (--bg-color doesn't touch it).
+/// Opaque unless --fg-color spelled out an alpha.
+pub(crate) fn text_foreground(theme: &Theme, colors: &Colors) -> Color {
+    match colors.foreground {
+        Some(HexColor { rgba, .. }) => Color {
+            r: rgba.r,
+            g: rgba.g,
+            b: rgba.b,
+            a: rgba.a,
+        },
+        None => theme.settings.foreground.unwrap(),
+    }
+}
+
+/// The fill alpha for a segment: --fg-alpha, unless it's unstyled text and --fg-color carries its own.
+fn text_alpha(unstyled: bool, colors: &Colors) -> u8 {
+    match colors.foreground {
+        Some(HexColor {
+            rgba,
+            explicit_alpha: true,
+        }) if unstyled => rgba.a,
+        _ => colors.foreground_alpha.to_u8(),
+    }
+}
+
+/// Highlights each line into styled segments, honoring the style overrides and --raw, reporting each line to any progress callback.
+pub(crate) fn highlight<'s>(
+    theme: &Theme,
+    ss: &SyntaxSet,
+    syntax: &SyntaxReference,
+    source_lines: &[&'s str],
+    continued: &[Option<Continuation>],
+    colors: &Colors,
+    layout: &Layout,
+) -> Vec<Vec<(Style, &'s str)>> {
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut scoped = ScopedHighlighter::new(syntax, theme, &colors.style_overrides);
+
+    // Raw lines aren't highlighted, so there's nothing to report on
+    let total = source_lines.len();
+    let report = |stage: RenderStage| {
+        if let (Some(progress), false) = (&layout.progress, layout.raw) {
+            progress(stage);
+        }
+    };
+    report(RenderStage::Highlighting { total });
+
+    // Raw mode skips highlighting entirely, every line becomes one default-styled span
+    let plain = Style {
+        foreground: theme.settings.foreground.unwrap(),
+        background: theme.settings.background.unwrap_or(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        }),
+        font_style: FontStyle::empty(),
+    };
+
+    if layout.raw {
+        return source_lines.iter().map(|&ln| vec![(plain, ln)]).collect();
+    }
+
+    let mut lines = Vec::with_capacity(total);
+    let mut start = 0;
+    while start < total {
+        // A line --wrap broke into rows is highlighted whole, so whatever it was in the middle
+        // of (a comment, a string) carries on into the next row
+        let end = (start + 1..total)
+            .find(|&i| !matches!(continued.get(i), Some(Some(_))))
+            .unwrap_or(total);
+
+        // Each row's text without the indentation a wrap gave it or the line ending it lost to
+        // the join, along with where that text starts in the joined line
+        let mut joined = String::new();
+        let mut pieces = Vec::with_capacity(end - start);
+        for (i, &row) in source_lines.iter().enumerate().take(end).skip(start) {
+            let (indent, dropped) = match continued.get(i) {
+                Some(Some(continuation)) if i > start => (
+                    continuation.indent.min(row.len()),
+                    continuation.dropped.as_str(),
+                ),
+                _ => (0, ""),
+            };
+            let body = match i + 1 < end {
+                true => row[indent..].trim_end_matches(['\r', '\n']),
+                false => &row[indent..],
+            };
+            // Put back exactly what the break took out, so a word split mid-way is one word again
+            joined.push_str(dropped);
+            pieces.push((row, indent, body.len(), joined.len()));
+            joined.push_str(body);
+        }
+
+        let segments = if colors.style_overrides.is_empty() {
+            highlighter.highlight_line(&joined, ss).unwrap()
+        } else {
+            scoped.highlight_line(&joined, ss)
+        };
+
+        // Where each segment falls in the joined line
+        let mut spans = Vec::with_capacity(segments.len());
+        let mut offset = 0;
+        for (style, text) in segments {
+            spans.push((style, offset, offset + text.len()));
+            offset += text.len();
+        }
+
+        // Cut the segments back up along the rows
+        for (row, indent, len, row_start) in pieces {
+            let row_end = row_start + len;
+            let mut line: Vec<(Style, &'s str)> = spans
+                .iter()
+                .filter(|&&(_, from, to)| from < row_end && to > row_start)
+                .map(|&(style, from, to)| {
+                    let from = from.max(row_start) - row_start + indent;
+                    let to = to.min(row_end) - row_start + indent;
+                    (style, &row[from..to])
+                })
+                .collect();
+            // The indentation a wrap added takes on the style of whatever it leads into
+            if indent > 0 {
+                let style = line.first().map_or(plain, |&(style, _)| style);
+                line.insert(0, (style, &row[..indent]));
+            }
+            // Rows broken off before the last lost their line ending to the join
+            if indent + len < row.len() {
+                let style = line.last().map_or(plain, |&(style, _)| style);
+                line.push((style, &row[indent + len..]));
+            }
+            lines.push(line);
+            report(RenderStage::Highlighted {
+                line: lines.len(),
+                total,
+            });
+        }
+        start = end;
+    }
+
+    lines
+}
+
+/// Highlights while keeping track of the scope stack, so style overrides can be matched against it.
+/// Slower than `HighlightLines`, which is why it's only used when there are overrides.
+struct ScopedHighlighter<'a> {
+    parse_state: ParseState,
+    stack: ScopeStack,
+    highlighter: Highlighter<'a>,
+    overrides: &'a [StyleOverride],
+}
+
+impl<'a> ScopedHighlighter<'a> {
+    fn new(syntax: &SyntaxReference, theme: &'a Theme, overrides: &'a [StyleOverride]) -> Self {
+        ScopedHighlighter {
+            parse_state: ParseState::new(syntax),
+            stack: ScopeStack::new(),
+            highlighter: Highlighter::new(theme),
+            overrides,
+        }
+    }
+
+    fn highlight_line<'l>(&mut self, line: &'l str, ss: &SyntaxSet) -> Vec<(Style, &'l str)> {
+        let ops = self.parse_state.parse_line(line, ss).unwrap();
+
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        for (i, op) in ops {
+            if i > pos {
+                segments.push((self.style(), &line[pos..i]));
+                pos = i;
+            }
+            self.stack.apply(&op).unwrap();
+        }
+        if pos < line.len() {
+            segments.push((self.style(), &line[pos..]));
+        }
+
+        segments
+    }
+
+    /// The theme's style for the current stack, with the best matching override on top
+    fn style(&self) -> Style {
+        let scopes = self.stack.as_slice();
+        let mut style = self.highlighter.style_for_stack(scopes);
+
+        let best = self
+            .overrides
+            .iter()
+            .filter_map(|o| o.selector.does_match(scopes).map(|power| (power, o)))
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        if let Some((_, o)) = best {
+            if o.plain {
+                return Style {
+                    foreground: self.highlighter.get_default().foreground,
+                    font_style: FontStyle::empty(),
+                    ..style
+                };
+            }
+            if let Some(foreground) = o.foreground {
+                style.foreground = foreground;
+            }
+            style.font_style |= o.font_style;
+        }
+
+        style
+    }
+}
+
+/// Cuts a line longer than `columns` down to its head and tail with a `…` between them.
+/// The line ending, if any, stays with the tail.
+fn truncate_middle(
+    line: Vec<(Style, &str)>,
+    columns: usize,
+    ellipsis: Style,
+) -> Vec<(Style, &str)> {
+    let content: usize = line
+        .iter()
+        .map(|(_, s)| s.trim_end_matches(['\r', '\n']).chars().count())
+        .sum();
+    if content <= columns {
+        return line;
+    }
+
+    // The ellipsis takes a column of its own, with any odd one going to the head
+    let tail = (columns - 1) / 2;
+    let head = columns - 1 - tail;
+    let cut = content - tail;
+
+    let slice = |s: &'_ str, from: usize, to: usize| {
+        let byte = |n: usize| s.char_indices().nth(n).map_or(s.len(), |(i, _)| i);
+        (byte(from), byte(to))
+    };
+
+    let mut out = Vec::new();
+    let mut elided = false;
+    let mut start = 0;
+    for (style, segment) in line {
+        let len = segment.chars().count();
+        let end = start + len;
+        if start < head {
+            let (from, to) = slice(segment, 0, len.min(head - start));
+            out.push((style, &segment[from..to]));
+        }
+        if end > cut {
+            if !elided {
+                out.push((ellipsis, "…"));
+                elided = true;
+            }
+            let (from, to) = slice(segment, cut.saturating_sub(start), len);
+            out.push((style, &segment[from..to]));
+        }
+        start = end;
+    }
+    // With no tail kept and no line ending to carry it, the ellipsis still goes at the end
+    if !elided {
+        out.push((ellipsis, "…"));
+    }
+    out
+}
+
+/// Replaces tabs with spaces up to the next stop, carrying the column over from earlier segments.
+fn expand_tabs(segment: &str, tab_width: usize, column: &mut usize) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for c in segment.chars() {
+        if c == '\t' {
+            let spaces = tab_width - *column % tab_width;
+            out.extend(std::iter::repeat_n(' ', spaces));
+            *column += spaces;
+        } else {
+            out.push(c);
+            if !is_zero_width(c) {
+                *column += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Text as it goes into the SVG. Under `Nbsp` every space and tab becomes a no-break space,
+/// which keeps its width without `xml:space`; a tab stands in for a single space either way.
+fn space_text(text: &str, mode: SpaceMode) -> String {
+    match mode {
+        SpaceMode::Preserve => text.to_string(),
+        SpaceMode::Nbsp => text.replace([' ', '\t'], "\u{A0}"),
+    }
+}
+
+/// Byte ranges of each line that sit inside a comment scope.
+fn comment_ranges(
+    lines: &[&str],
+    syntax: &SyntaxReference,
+    ss: &SyntaxSet,
+) -> Vec<Vec<Range<usize>>> {
+    let comment = Scope::new("comment").unwrap();
+    let mut parse_state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let in_comment = |stack: &ScopeStack| stack.as_slice().iter().any(|s| comment.is_prefix_of(*s));
+
+    lines
+        .iter()
+        .map(|line| {
+            let ops = parse_state.parse_line(line, ss).unwrap();
+
+            let mut ranges = Vec::new();
+            let mut pos = 0;
+            for (i, op) in ops {
+                if i > pos && in_comment(&stack) {
+                    ranges.push(pos..i);
+                }
+                pos = pos.max(i);
+                stack.apply(&op).unwrap();
+            }
+            if pos < line.len() && in_comment(&stack) {
+                ranges.push(pos..line.len());
+            }
+
+            ranges
+        })
+        .collect()
+}
+
+/// Name of the syntax `code_to_svg` would highlight an input with this extension as.
+pub(crate) fn detect_language(ss: &SyntaxSet, ext: &str) -> String {
+    ss.find_syntax_by_token(ext)
+        .unwrap_or_else(|| ss.find_syntax_plain_text())
+        .name
+        .clone()
+}
+
+/// Builds the font-family list: the loaded font, then any fallbacks in order.
+/// Fonts without a name table fall back to the requested family, then to any monospace font.
+fn font_family(font: &FontConfig) -> String {
+    let name = match font.glyphs.name() {
+        Some(name) => name,
+        None if !font.family.is_empty() => &font.family,
+        None => "monospace",
+    };
+
+    std::iter::once(name)
+        .chain(font.fallbacks.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Adds a drop shadow <filter> definition, covering `region` (x, y, width, height) in the user space of whatever uses it.
+fn add_shadow(
+    doc: &mut Document,
+    id: &str,
+    (x_offset, y_offset): (f64, f64),
+    blur: f64,
+    (x, y, width, height): (f64, f64, f64, f64),
+) {
     // Gaussian blur the alpha channel
     let gaussian = FilterEffectGaussianBlur::new()
         .set("in", "SourceAlpha")
@@ -147,22 +2006,94 @@ fn add_shadow(elem: Document, id: &str, x_offset: f64, y_offset: f64, blur: f64)
         .set("dx", x_offset)
         .set("dy", y_offset);
 
+    // A solid black shadow looks pasted on, so it only goes partway
+    let flood = FilterEffectFlood::new()
+        .set("flood-color", "#000000")
+        .set("flood-opacity", SHADOW_OPACITY)
+        .set("result", "color");
+    let composite = FilterEffectComposite::new()
+        .set("in", "color")
+        .set("in2", "offsetblur")
+        .set("operator", "in")
+        .set("result", "shadow");
+
     // Merge the offset blur with the original graphic
     let merge = FilterEffectMerge::new()
-        .add(FilterEffectMergeNode::new())
+        .add(FilterEffectMergeNode::new().set("in", "shadow"))
         .add(FilterEffectMergeNode::new().set("in", "SourceGraphic"));
 
     // Build the <filter> element
+    // The default region is only 10% bigger than the card, too tight for a soft shadow
     let filter = Filter::new()
         .set("id", id)
         .set("filterUnits", "userSpaceOnUse")
+        .set("x", x)
+        .set("y", y)
+        .set("width", width)
+        .set("height", height)
         .add(gaussian)
         .add(offset)
+        .add(flood)
+        .add(composite)
         .add(merge);
 
     // Wrap it in <defs> and append
     let defs = Definitions::new().add(filter);
-    elem.add(defs)
+    doc.append(defs);
+}
+
+/// Adds a plain Gaussian blur <filter> definition, with room for a thin shape to spread on every side.
+fn add_blur(doc: &mut Document, id: &str, blur: f64) {
+    let gaussian = FilterEffectGaussianBlur::new().set("stdDeviation", blur);
+    let filter = Filter::new()
+        .set("id", id)
+        .set("x", "-100%")
+        .set("y", "-100%")
+        .set("width", "300%")
+        .set("height", "300%")
+        .add(gaussian);
+
+    let defs = Definitions::new().add(filter);
+    doc.append(defs);
+}
+
+/// Adds a glow <filter> definition (a blurred, flood-colored copy of the glyphs under the originals).
+fn add_glow(doc: &mut Document, id: &str, color: &str, blur: f64) {
+    // Blur the glyph shapes
+    let gaussian = FilterEffectGaussianBlur::new()
+        .set("in", "SourceAlpha")
+        .set("stdDeviation", blur)
+        .set("result", "blur");
+
+    // Paint the glow color through the blurred shapes
+    let flood = FilterEffectFlood::new()
+        .set("flood-color", color)
+        .set("result", "color");
+    let composite = FilterEffectComposite::new()
+        .set("in", "color")
+        .set("in2", "blur")
+        .set("operator", "in")
+        .set("result", "glow");
+
+    // Keep the crisp text on top
+    let merge = FilterEffectMerge::new()
+        .add(FilterEffectMergeNode::new().set("in", "glow"))
+        .add(FilterEffectMergeNode::new().set("in", "SourceGraphic"));
+
+    // Leave room for the blur to spread past the text's bounding box
+    let filter = Filter::new()
+        .set("id", id)
+        .set("x", "-50%")
+        .set("y", "-50%")
+        .set("width", "200%")
+        .set("height", "200%")
+        .add(gaussian)
+        .add(flood)
+        .add(composite)
+        .add(merge);
+
+    let defs = Definitions::new().add(filter);
+    doc.append(defs);
 }
 
 /// Adds a <clipPath> definition (with a single <rect>) to the document’s <defs>.
@@ -179,12 +2110,93 @@ fn add_clip_path(doc: &mut Document, id: &str, x: f64, y: f64, width: f64, heigh
     doc.append(defs);
 }
 
+/// Adds a repeating dots or grid <pattern> definition to the document’s <defs>.
+fn add_pattern(doc: &mut Document, id: &str, kind: BgPattern, color: &str, spacing: f64) {
+    let mut pattern = Pattern::new()
+        .set("id", id)
+        .set("patternUnits", "userSpaceOnUse")
+        .set("width", spacing)
+        .set("height", spacing);
+
+    pattern = match kind {
+        BgPattern::Dots => pattern.add(
+            Circle::new()
+                .set("cx", spacing / 2.0)
+                .set("cy", spacing / 2.0)
+                .set("r", (spacing / 16.0).max(1.0))
+                .set("fill", color),
+        ),
+        // Only the top and left edges, so neighboring cells don't double up the lines
+        BgPattern::Grid => pattern.add(
+            Path::new()
+                .set("d", format!("M {0} 0 L 0 0 0 {0}", spacing))
+                .set("fill", "none")
+                .set("stroke", color)
+                .set("stroke-width", 1),
+        ),
+    };
+
+    let defs = Definitions::new().add(pattern);
+    doc.append(defs);
+}
+
+/// Adds a left-to-right <linearGradient> from transparent to `color` to the document’s <defs>.
+fn add_fade(doc: &mut Document, id: &str, color: &str, opacity: f64) {
+    let gradient = LinearGradient::new()
+        .set("id", id)
+        .add(
+            Stop::new()
+                .set("offset", 0)
+                .set("stop-color", color)
+                .set("stop-opacity", 0),
+        )
+        .add(
+            Stop::new()
+                .set("offset", 1)
+                .set("stop-color", color)
+                .set("stop-opacity", opacity),
+        );
+
+    let defs = Definitions::new().add(gradient);
+    doc.append(defs);
+}
+
+/// Defines a radial gradient clear in the middle that darkens to `strength` opacity at the edges.
+fn add_vignette(doc: &mut Document, id: &str, strength: f64) {
+    let gradient = RadialGradient::new()
+        .set("id", id)
+        .add(
+            Stop::new()
+                .set("offset", 0.5)
+                .set("stop-color", "#000000")
+                .set("stop-opacity", 0),
+        )
+        .add(
+            Stop::new()
+                .set("offset", 1)
+                .set("stop-color", "#000000")
+                .set("stop-opacity", strength),
+        );
+
+    let defs = Definitions::new().add(gradient);
+    doc.append(defs);
+}
+
+/// Moves an element `dx` to the right, leaving it untouched when there's nowhere to go.
+fn shift_x<E: Node>(mut elem: E, dx: f32) -> E {
+    if dx != 0.0 {
+        elem.assign("transform", format!("translate({} 0)", dx));
+    }
+    elem
+}
+
 /// Returns a new <rect> with corner‐radius applied.
 fn add_corner_radius(rect: Rectangle, r: f64) -> Rectangle {
     rect.set("rx", r).set("ry", r)
 }
 
 /// Returns a new element shifted to (x,y) with a “px” suffix.
+#[allow(dead_code)]
 fn move_element<E: Node>(elem: &mut E, x: f64, y: f64) -> &mut E {
     elem.assign("x", format!("{:.2}px", x));
     elem.assign("y", format!("{:.2}px", y));
@@ -192,6 +2204,7 @@ fn move_element<E: Node>(elem: &mut E, x: f64, y: f64) -> &mut E {
 }
 
 /// Returns a new element given a stroke outline.
+#[allow(dead_code)]
 fn add_outline<'a, E: Node>(elem: &'a mut E, width: f64, color: &str) -> &'a mut E {
     elem.assign("stroke", color);
     elem.assign("stroke-width", format!("{:.2}", width));
@@ -199,6 +2212,7 @@ fn add_outline<'a, E: Node>(elem: &'a mut E, width: f64, color: &str) -> &'a mut
 }
 
 /// Sets width/height attributes
+#[allow(dead_code)]
 pub fn set_dimensions<E: Node>(elem: &mut E, width: f64, height: f64) -> &mut E {
     elem.assign("width", width);
     elem.assign("height", height);
@@ -217,3 +2231,154 @@ pub fn get_dimensions<E: Node>(elem: &E) -> (u32, u32) {
 fn dimension_to_int(s: &str) -> u32 {
     s.trim_end_matches("px").parse::<u32>().unwrap_or(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load_font, prepare_input, Cli};
+    use clap::Parser;
+    use two_face::theme::EmbeddedThemeName;
+    use usvg::fontdb::Database;
+
+    /// Lays `contents` out as sia would with `args`, in the first monospaced font the system has.
+    fn lay_out(contents: &str, args: &[&str]) -> (Document, FontConfig) {
+        try_lay_out(contents, args).unwrap()
+    }
+
+    fn try_lay_out(contents: &str, args: &[&str]) -> Result<(Document, FontConfig), Error> {
+        let mut fontdb = Database::new();
+        fontdb.load_system_fonts();
+        let family = fontdb
+            .faces()
+            .filter(|face| face.monospaced)
+            .chain(fontdb.faces())
+            .find_map(|face| face.families.first())
+            .map(|family| family.0.clone())
+            .expect("no system fonts to lay out with");
+
+        let cli = [
+            "sia",
+            "--font",
+            &family,
+            "--font-size",
+            "20",
+            "--input",
+            contents,
+        ];
+        let cli = Cli::try_parse_from(cli.iter().chain(args)).unwrap();
+        let (glyphs, data) = load_font(&cli, &fontdb, &family).unwrap();
+        let font = FontConfig {
+            glyphs,
+            family,
+            data,
+            size: 20.0,
+            weight: None,
+            fallbacks: Vec::new(),
+            line_height: 1.0,
+        };
+
+        let mut input = cli.input[0].clone();
+        prepare_input(&cli, &mut input)?;
+
+        let theme = two_face::theme::extra()
+            .get(EmbeddedThemeName::Base16OceanDark)
+            .clone();
+        let doc = code_to_svg(
+            &theme,
+            &two_face::syntax::extra_newlines(),
+            &input,
+            &font,
+            &Colors::from_cli(&cli),
+            &Layout::from_cli(&cli),
+        )?;
+        Ok((doc, font))
+    }
+
+    /// The baseline of every `<text>` in the document, by what it says.
+    fn baselines(doc: &Document) -> HashMap<String, String> {
+        let svg = doc.to_string();
+        let tree = roxmltree::Document::parse(&svg).unwrap();
+        tree.descendants()
+            .filter(|node| node.has_tag_name("text"))
+            .filter_map(|node| {
+                let text: String = node
+                    .descendants()
+                    .filter(|n| n.is_text())
+                    .filter_map(|n| n.text())
+                    .collect();
+                Some((text.trim().to_string(), node.attribute("y")?.to_string()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn canvas_rounds_up_to_whole_pixels() {
+        for n in 1..=12 {
+            let line = "0".repeat(n);
+            let contents = vec![line.as_str(); n].join("\n");
+            let (doc, font) = lay_out(&contents, &["--padding", "0"]);
+            let (width, height) = get_dimensions(&doc);
+
+            // Rounding to nearest would shave the partial pixel off the last glyph or line
+            let advance = font.glyphs.metrics('0', font.size).advance_width;
+            assert!(width as f32 >= n as f32 * advance, "{} columns", n);
+            assert!(height as f32 >= n as f32 * line_pitch(&font), "{} lines", n);
+        }
+    }
+
+    #[test]
+    fn final_newline_ends_the_last_line() {
+        let (without, _) = lay_out("let a = 1;", &[]);
+        let (with, font) = lay_out("let a = 1;\n", &[]);
+        assert_eq!(get_dimensions(&with), get_dimensions(&without));
+
+        // Kept, the empty row after it adds a line's worth of height
+        let (kept, _) = lay_out("let a = 1;\n", &["--preserve-empty-last-line"]);
+        let grown = get_dimensions(&kept).1 - get_dimensions(&with).1;
+        assert!((grown as f32 - line_pitch(&font)).abs() <= 1.0);
+    }
+
+    #[test]
+    fn nesting_prefixes_ids_but_not_code() {
+        let code = "see id=\"main\" and url(#x)";
+        let (doc, _) = lay_out(code, &["--shadow"]);
+        let nested = nest(doc, "panel0", 0.0, 0.0).to_string();
+        assert!(nested.contains(code), "{}", nested);
+        assert!(nested.contains("id=\"panel0-card-shadow\""));
+        assert!(nested.contains("url(#panel0-card-shadow)"));
+    }
+
+    #[test]
+    fn annotations_follow_the_gutter_numbers() {
+        let (doc, _) = lay_out(
+            "first\nsecond\nthird",
+            &[
+                "--line-numbers",
+                "--start-line",
+                "10",
+                "--annotate",
+                "11=note",
+            ],
+        );
+        let y = baselines(&doc);
+        assert_eq!(y["note"], y["second"]);
+        assert!(try_lay_out(
+            "first\nsecond",
+            &[
+                "--line-numbers",
+                "--start-line",
+                "10",
+                "--annotate",
+                "2=note"
+            ]
+        )
+        .is_err());
+
+        // Wrapping pushes the later lines down, and the note goes with its line
+        let long = "word ".repeat(20);
+        let contents = format!("first\n{}\nthird", long.trim());
+        let (doc, _) = lay_out(&contents, &["--wrap", "20", "--annotate", "3=note"]);
+        let y = baselines(&doc);
+        assert_eq!(y["note"], y["third"]);
+    }
+}
@@ -1,15 +1,777 @@
-use crate::FontConfig;
+use crate::{Continuation, ControlChars, FontConfig, IndentStyle, LineRange, SiaError};
+use encoding_rs::Encoding;
+use fontdue::Font;
+use regex::Regex;
 use rusttype::{self, Scale};
-use std::fs;
+use std::collections::HashSet;
+use std::env;
+use std::path::{Component, Path};
+use std::process::Command;
+use two_face::re_exports::syntect::highlighting::{Color, Theme};
 
 pub fn get_canvas_height(num_lines: usize, font: &FontConfig) -> f32 {
+    line_pitch(font) * num_lines as f32
+}
+
+/// The distance from one baseline to the next: the font's own line spacing, scaled by --line-height.
+pub fn line_pitch(font: &FontConfig) -> f32 {
+    let v_metrics = v_metrics(font);
+    (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap) * font.line_height
+}
+
+/// How far below the top of its row a line's baseline sits. Any leading is split evenly above and
+/// below the glyphs, as CSS does, so neither the first line's ascenders nor the last's descenders are cut off.
+pub fn first_baseline(font: &FontConfig) -> f32 {
+    let v_metrics = v_metrics(font);
+    let glyphs = v_metrics.ascent - v_metrics.descent;
+    (line_pitch(font) - glyphs) / 2.0 + v_metrics.ascent
+}
+
+fn v_metrics(font: &FontConfig) -> rusttype::VMetrics {
     // Read into RUSTTYPE as fontdue sucks at height
     let font_font = rusttype::Font::try_from_bytes(&font.data).unwrap();
+    font_font.v_metrics(Scale::uniform(font.size))
+}
+
+/// Checks whether a font looks monospace by comparing the advances of glyphs that differ
+/// wildly in a proportional font.
+pub fn is_monospace(font: &Font, size: f32) -> bool {
+    let probes = ['i', 'm', 'W', '0', '.', ' '];
+
+    let advances: Vec<f32> = probes
+        .iter()
+        .filter(|&&c| font.lookup_glyph_index(c) != 0)
+        .map(|&c| font.metrics(c, size).advance_width)
+        .collect();
+
+    // Without any probes to compare against, assume the best
+    let Some(&first) = advances.first() else {
+        return true;
+    };
+
+    // Allow for a little rounding noise between glyphs
+    advances.iter().all(|a| (a - first).abs() < 0.5)
+}
+
+/// Lists the distinct characters in `text` that the font has no glyph for, in order of appearance.
+pub fn missing_glyphs(font: &Font, text: &str) -> Vec<char> {
+    let mut missing = Vec::new();
+    // Code reuses a small alphabet, so each character is only looked up the first time it shows up
+    let mut seen = HashSet::new();
+
+    for c in text.chars() {
+        // Control characters (newlines, tabs) are never drawn, so they don't need a glyph
+        if c.is_control() || !seen.insert(c) {
+            continue;
+        }
+
+        // Index 0 is .notdef, which is what a font hands back for anything it can't draw
+        if font.lookup_glyph_index(c) == 0 {
+            missing.push(c);
+        }
+    }
+
+    missing
+}
+
+/// Whether `c` takes up no horizontal space of its own: controls, combining marks, and the
+/// invisible joiners/selectors. fontdue still reports an advance for some of these.
+pub fn is_zero_width(c: char) -> bool {
+    c.is_control()
+        || matches!(c,
+            '\u{0300}'..='\u{036F}'     // Combining diacritical marks
+            | '\u{1AB0}'..='\u{1AFF}'   // ...extended
+            | '\u{1DC0}'..='\u{1DFF}'   // ...supplement
+            | '\u{20D0}'..='\u{20FF}'   // ...for symbols
+            | '\u{FE20}'..='\u{FE2F}'   // Combining half marks
+            | '\u{200B}'..='\u{200F}'   // Zero-width space, joiners, direction marks
+            | '\u{2060}'..='\u{2064}'   // Word joiner and invisible operators
+            | '\u{FE00}'..='\u{FE0F}'   // Variation selectors
+            | '\u{FEFF}'                 // Zero-width no-break space
+            | '\u{E0100}'..='\u{E01EF}' // Variation selectors supplement
+        )
+}
+
+/// Whether `c` is in the box drawing or block elements ranges (├ └ │ █ and friends).
+pub fn is_box_drawing(c: char) -> bool {
+    matches!(c, '\u{2500}'..='\u{259F}')
+}
+
+/// WCAG relative luminance of a color, ignoring alpha.
+pub fn relative_luminance(c: Color) -> f32 {
+    let [r, g, b] = [c.r, c.g, c.b].map(|v| {
+        let v = v as f32 / 255.0;
+        if v <= 0.03928 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// WCAG contrast ratio between two colors, from 1 (identical) to 21 (black on white).
+pub fn contrast_ratio(a: Color, b: Color) -> f32 {
+    let (la, lb) = (relative_luminance(a), relative_luminance(b));
+    (la.max(lb) + 0.05) / (la.min(lb) + 0.05)
+}
+
+/// Blends `fg` towards white on dark backgrounds (black on light ones) until it reaches `min` contrast with `bg`.
+/// Returns `None` when it already does.
+pub fn ensure_contrast(fg: Color, bg: Color, min: f32) -> Option<Color> {
+    if contrast_ratio(fg, bg) >= min {
+        return None;
+    }
+
+    let target = if relative_luminance(bg) < 0.5 {
+        255.0
+    } else {
+        0.0
+    };
+    let blend = |v: u8, t: f32| (v as f32 + (target - v as f32) * t).round() as u8;
+
+    // Small steps keep as much of the original hue as possible; a full blend always gets there
+    let adjusted = (1..=20)
+        .map(|step| {
+            let t = step as f32 / 20.0;
+            Color {
+                r: blend(fg.r, t),
+                g: blend(fg.g, t),
+                b: blend(fg.b, t),
+                a: fg.a,
+            }
+        })
+        .find(|&c| contrast_ratio(c, bg) >= min);
+
+    // Ratios above what pure black or white can reach just get the extreme
+    Some(adjusted.unwrap_or(Color {
+        r: target as u8,
+        g: target as u8,
+        b: target as u8,
+        a: fg.a,
+    }))
+}
+
+/// Pushes `fg` `factor` times as far from `bg` along each channel, clamped to the valid range.
+pub fn boost_contrast(fg: Color, bg: Color, factor: f32) -> Color {
+    let push = |v: u8, from: u8| {
+        (from as f32 + (v as f32 - from as f32) * factor)
+            .round()
+            .clamp(0.0, 255.0) as u8
+    };
+    Color {
+        r: push(fg.r, bg.r),
+        g: push(fg.g, bg.g),
+        b: push(fg.b, bg.b),
+        a: fg.a,
+    }
+}
+
+/// Flips a color's lightness in HSL, keeping its hue and saturation (and alpha).
+pub fn invert_lightness(c: Color) -> Color {
+    let [r, g, b] = [c.r, c.g, c.b].map(|v| v as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+
+    // Greys have no hue to keep, so only the lightness matters
+    if max == min {
+        let v = ((1.0 - l) * 255.0).round() as u8;
+        return Color {
+            r: v,
+            g: v,
+            b: v,
+            a: c.a,
+        };
+    }
+
+    let d = max - min;
+    let s = if l > 0.5 {
+        d / (2.0 - max - min)
+    } else {
+        d / (max + min)
+    };
+    let h = if max == r {
+        (g - b) / d + if g < b { 6.0 } else { 0.0 }
+    } else if max == g {
+        (b - r) / d + 2.0
+    } else {
+        (r - g) / d + 4.0
+    } / 6.0;
+
+    // Back to RGB with the mirrored lightness
+    let l = 1.0 - l;
+    let q = if l < 0.5 {
+        l * (1.0 + s)
+    } else {
+        l + s - l * s
+    };
+    let p = 2.0 * l - q;
+    let channel = |t: f32| {
+        let t = t.rem_euclid(1.0);
+        let v = if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        };
+        (v * 255.0).round() as u8
+    };
+
+    Color {
+        r: channel(h + 1.0 / 3.0),
+        g: channel(h),
+        b: channel(h - 1.0 / 3.0),
+        a: c.a,
+    }
+}
+
+/// Returns a copy of the theme with every color's lightness inverted, turning dark themes light and vice versa.
+pub fn invert_theme(theme: &Theme) -> Theme {
+    let mut inverted = theme.clone();
+    let settings = &mut inverted.settings;
+
+    for color in [
+        &mut settings.foreground,
+        &mut settings.background,
+        &mut settings.line_highlight,
+        &mut settings.selection,
+        &mut settings.selection_foreground,
+        &mut settings.gutter,
+        &mut settings.gutter_foreground,
+    ] {
+        *color = color.map(invert_lightness);
+    }
+
+    // Token colors tuned for one background would wash out on the other, so they flip too
+    for item in &mut inverted.scopes {
+        item.style.foreground = item.style.foreground.map(invert_lightness);
+        item.style.background = item.style.background.map(invert_lightness);
+    }
+
+    inverted
+}
+
+/// Best-effort guess at whether the system (or terminal) is using a dark appearance.
+pub fn prefers_dark() -> bool {
+    // Terminals like rxvt and konsole export "fg;bg" (sometimes "fg;default;bg") as ANSI indices
+    if let Ok(colorfgbg) = env::var("COLORFGBG") {
+        if let Some(Ok(bg)) = colorfgbg.rsplit(';').next().map(str::parse::<u8>) {
+            // 7 (white) and the bright range above 8 are the light backgrounds
+            return !(bg == 7 || bg > 8);
+        }
+    }
+
+    // macOS only sets this key at all when dark mode is on
+    if cfg!(target_os = "macos") {
+        if let Ok(out) = Command::new("defaults")
+            .args(["read", "-g", "AppleInterfaceStyle"])
+            .output()
+        {
+            return String::from_utf8_lossy(&out.stdout).trim() == "Dark";
+        }
+    }
+
+    // Most code themes are dark, so it's the safer guess
+    true
+}
+
+/// Removes ANSI SGR sequences (`ESC [ ... m`) so pre-colored terminal output renders cleanly.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.peek() == Some(&'[') {
+            // Look ahead over the parameters without committing, in case this isn't SGR
+            let rest: String = chars
+                .clone()
+                .skip(1)
+                .take_while(|c| c.is_ascii_digit() || *c == ';')
+                .collect();
+            let mut lookahead = chars.clone().skip(1 + rest.len());
+            if lookahead.next() == Some('m') {
+                // Skip the '[', the parameters, and the final 'm'
+                for _ in 0..rest.len() + 2 {
+                    chars.next();
+                }
+                continue;
+            }
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+/// Strips or shows the control characters in `s`, other than the tabs and line breaks layout relies on.
+/// C0 controls and DEL have Unicode control pictures (U+2400 onwards), the rest show as U+FFFD.
+pub fn replace_control_chars(s: &str, policy: ControlChars) -> String {
+    s.chars()
+        .filter_map(|c| match c {
+            '\t' | '\n' | '\r' => Some(c),
+            c if !c.is_control() => Some(c),
+            _ => match policy {
+                ControlChars::Strip => None,
+                ControlChars::Show => Some(match c {
+                    '\0'..='\x1f' => char::from_u32(0x2400 + c as u32).unwrap_or(c),
+                    '\x7f' => '\u{2421}',
+                    _ => char::REPLACEMENT_CHARACTER,
+                }),
+                ControlChars::Ignore => Some(c),
+            },
+        })
+        .collect()
+}
+
+/// Rewrites each line's indentation in one style, keeping its width in columns with tab stops every
+/// `tab_width`. Also returns whether the input mixed tab and space indentation to begin with.
+pub fn normalize_indent(s: &str, style: IndentStyle, tab_width: usize) -> (String, bool) {
+    let (mut tabs, mut spaces) = (false, false);
+
+    let out = s
+        .split_inclusive('\n')
+        .map(|line| {
+            let body = line.trim_start_matches([' ', '\t']);
+            let indent = &line[..line.len() - body.len()];
+            tabs |= indent.contains('\t');
+            spaces |= indent.contains(' ');
+
+            let width = indent.chars().fold(0, |column, c| match c {
+                '\t' => column + tab_width - column % tab_width,
+                _ => column + 1,
+            });
+            let indent = match style {
+                IndentStyle::Spaces => " ".repeat(width),
+                IndentStyle::Tabs => {
+                    format!(
+                        "{}{}",
+                        "\t".repeat(width / tab_width),
+                        " ".repeat(width % tab_width)
+                    )
+                }
+            };
+            format!("{}{}", indent, body)
+        })
+        .collect();
+
+    (out, tabs && spaces)
+}
+
+/// Keeps only the given line ranges, in file order with overlaps merged, and puts a `⋯` row wherever
+/// lines were skipped between two of them. Also returns the 1-based line each row came from, `None`
+/// for the gap rows.
+pub fn select_lines(
+    s: &str,
+    ranges: &[LineRange],
+) -> Result<(String, Vec<Option<usize>>), SiaError> {
+    let lines: Vec<&str> = s.split_inclusive('\n').collect();
+
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|range| range.start);
+
+    let mut out = String::new();
+    let mut origins = Vec::new();
+    let mut next = 1;
+
+    for range in ranges {
+        if range.start > lines.len() {
+            return Err(SiaError::InvalidConfig(format!(
+                "can't show line {}, there are only {}",
+                range.start,
+                lines.len()
+            )));
+        }
+
+        // Overlapping and touching ranges run together without a gap
+        let start = range.start.max(next);
+        if start > next && !origins.is_empty() {
+            out.push_str("⋯\n");
+            origins.push(None);
+        }
+
+        for n in start..=range.end.min(lines.len()) {
+            let line = lines[n - 1];
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push('\n');
+            }
+            origins.push(Some(n));
+        }
+        next = next.max(range.end + 1);
+    }
+
+    Ok((out, origins))
+}
+
+/// Trims trailing spaces and tabs from every line, keeping the line endings intact.
+pub fn strip_trailing_whitespace(s: &str) -> String {
+    s.split_inclusive('\n')
+        .map(|line| {
+            let (body, ending) = match line.strip_suffix("\r\n") {
+                Some(body) => (body, "\r\n"),
+                None => match line.strip_suffix('\n') {
+                    Some(body) => (body, "\n"),
+                    None => (line, ""),
+                },
+            };
+            format!("{}{}", body.trim_end_matches([' ', '\t']), ending)
+        })
+        .collect()
+}
+
+/// Keeps only the first line matching `pattern` plus `context` lines either side of it.
+pub fn focus_on_match(s: &str, pattern: &Regex, context: usize) -> Option<String> {
+    let lines: Vec<&str> = s.split_inclusive('\n').collect();
+    let hit = lines
+        .iter()
+        .position(|line| pattern.is_match(line.trim_end_matches(['\r', '\n'])))?;
+
+    let start = hit.saturating_sub(context);
+    let end = (hit + context + 1).min(lines.len());
+
+    Some(lines[start..end].concat())
+}
+
+/// Decodes file bytes from a legacy (or explicit) encoding, dropping any BOM.
+pub fn decode(bytes: &[u8], encoding: &'static Encoding) -> String {
+    // A BOM overrides the label, as browsers do
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// Reflows prose: single newlines become spaces, blank lines still separate paragraphs.
+pub fn collapse_prose(s: &str) -> String {
+    let mut paragraphs = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+
+    for line in s.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(current.join(" "));
+                current.clear();
+            }
+        } else {
+            current.push(line.trim());
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current.join(" "));
+    }
+
+    paragraphs.join("\n\n")
+}
+
+/// Wraps every line to at most `columns` characters, breaking at spaces where it can and
+/// mid-word only when a single word is too long. Continuation rows take the indentation of the
+/// line they came from. Also returns how each row continues the one above, keeping `continued`'s
+/// marks for rows that were already continuations before this wrap.
+pub fn wrap_lines(
+    s: &str,
+    columns: usize,
+    continued: &[Option<Continuation>],
+) -> (String, Vec<Option<Continuation>>) {
+    let columns = columns.max(1);
+    let mut out = String::with_capacity(s.len());
+    let mut rows = Vec::new();
+
+    for (i, line) in s.split_inclusive('\n').enumerate() {
+        let (body, ending) = match line.strip_suffix('\n') {
+            Some(body) => (body, "\n"),
+            None => (line, ""),
+        };
+        rows.push(continued.get(i).cloned().flatten());
+
+        // Only repeated while it leaves at least half the row for the text itself
+        let lead = body.len() - body.trim_start_matches([' ', '\t']).len();
+        let indent = match &body[..lead] {
+            indent if indent.chars().count() * 2 <= columns => indent,
+            _ => "",
+        };
+
+        let mut rest = body;
+        let mut width = columns;
+        // A space inside the indentation is no place to break
+        let mut lead = lead;
+        while rest.chars().count() > width {
+            // Byte offset of the first character past the limit
+            let limit = rest
+                .char_indices()
+                .nth(width)
+                .map_or(rest.len(), |(i, _)| i);
+
+            // Prefer the last space within the limit so words stay whole
+            let split = match rest[..limit].rfind(' ') {
+                Some(space) if space > lead => space + 1,
+                _ => limit,
+            };
+
+            let row = rest[..split].trim_end_matches(' ');
+            out.push_str(row);
+            out.push('\n');
+            out.push_str(indent);
+            rows.push(Some(Continuation {
+                indent: indent.len(),
+                dropped: rest[row.len()..split].to_string(),
+            }));
+            rest = &rest[split..];
+            width = columns - indent.chars().count();
+            lead = 0;
+        }
+
+        out.push_str(rest);
+        out.push_str(ending);
+    }
+
+    (out, rows)
+}
+
+/// Reads the system clipboard as text.
+#[cfg(feature = "clipboard")]
+pub fn read_clipboard() -> Result<String, SiaError> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_text())
+        .map_err(|e| SiaError::Message(format!("couldn't read the clipboard ({})", e)))
+}
+
+/// Builds without the clipboard feature have no way to read it.
+#[cfg(not(feature = "clipboard"))]
+pub fn read_clipboard() -> Result<String, SiaError> {
+    Err(SiaError::Message(
+        "sia was built without clipboard support, rebuild it with --features clipboard".into(),
+    ))
+}
+
+/// Crops a rendered pixmap to the bounding box of everything that differs from the background
+/// (taken from the top-left pixel), keeping `padding` px around it where the canvas allows.
+pub fn trim_pixmap(map: &tiny_skia::Pixmap, padding: u32) -> Option<tiny_skia::Pixmap> {
+    let (width, height) = (map.width(), map.height());
+    let pixels = map.pixels();
+    let background = *pixels.first()?;
+
+    let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+    for y in 0..height {
+        for x in 0..width {
+            if pixels[(y * width + x) as usize] != background {
+                left = left.min(x);
+                right = right.max(x);
+                top = top.min(y);
+                bottom = bottom.max(y);
+            }
+        }
+    }
+
+    // Nothing but background, so there's nothing to trim towards
+    if left > right {
+        return None;
+    }
+
+    let left = left.saturating_sub(padding);
+    let top = top.saturating_sub(padding);
+    let right = (right + padding).min(width - 1);
+    let bottom = (bottom + padding).min(height - 1);
+
+    map.clone_rect(tiny_skia::IntRect::from_ltrb(
+        left as i32,
+        top as i32,
+        right as i32 + 1,
+        bottom as i32 + 1,
+    )?)
+}
+
+/// The parts of `path` below `root` (the working directory when not given), or all of them for a path outside it.
+pub fn path_components(path: &Path, root: Option<&Path>) -> Vec<String> {
+    let root = match root {
+        Some(root) => root.to_path_buf(),
+        None => env::current_dir().unwrap_or_default(),
+    };
+    // Compared canonically, so ./src/../src/lib.rs and symlinked roots still line up
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let root = root.canonicalize().unwrap_or(root);
+    let relative = path.strip_prefix(&root).unwrap_or(&path);
+
+    relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Writes `bytes` to a temporary file beside `path` and renames it into place, so anything
+/// watching `path` sees either the old file or the whole new one, never half of it.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+
+    let result = fs_err::write(&temp, bytes).and_then(|()| fs_err::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Runs `git diff` on a file against the index, from the file's own directory so any repo works.
+pub fn git_diff(path: &Path) -> Result<String, SiaError> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty());
+    let name = path.file_name().unwrap_or(path.as_os_str());
+
+    let mut git = Command::new("git");
+    if let Some(dir) = dir {
+        git.current_dir(dir);
+    }
+    let out = git
+        .args(["diff", "--no-color", "--no-ext-diff", "-U0", "--"])
+        .arg(name)
+        .output()
+        .map_err(|e| SiaError::Message(format!("couldn't run git: {}", e)))?;
+
+    if !out.status.success() {
+        return Err(SiaError::Message(format!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Downloads a URL, following redirects. Anything but a 200 is an error.
+#[cfg(feature = "url")]
+pub fn fetch_url(url: &str) -> Result<Vec<u8>, SiaError> {
+    let response = match ureq::get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => {
+            return Err(SiaError::Http {
+                url: url.to_string(),
+                status,
+            })
+        }
+        Err(e) => return Err(SiaError::Message(format!("couldn't fetch {}: {}", url, e))),
+    };
+
+    if response.status() != 200 {
+        return Err(SiaError::Http {
+            url: url.to_string(),
+            status: response.status(),
+        });
+    }
+
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Runs `git diff --no-index` between two files, which works outside any repo.
+pub fn git_diff_files(before: &Path, after: &Path) -> Result<String, SiaError> {
+    let out = Command::new("git")
+        .args([
+            "diff",
+            "--no-index",
+            "--no-color",
+            "--no-ext-diff",
+            "-U0",
+            "--",
+        ])
+        .arg(before)
+        .arg(after)
+        .output()
+        .map_err(|e| SiaError::Message(format!("couldn't run git: {}", e)))?;
+
+    // Exits with 1 when the files differ, which is the expected case here
+    if !matches!(out.status.code(), Some(0 | 1)) {
+        return Err(SiaError::Message(format!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Zero-based line numbers in the new file that a unified diff adds.
+pub fn added_lines(patch: &str) -> Vec<usize> {
+    changed_lines(patch, '+')
+}
+
+/// Zero-based line numbers in the old file that a unified diff removes.
+pub fn removed_lines(patch: &str) -> Vec<usize> {
+    changed_lines(patch, '-')
+}
+
+/// Zero-based line numbers of the rows marked with `sign`, counted in the file whose hunk range
+/// carries that same sign ('+' for the new file, '-' for the old).
+fn changed_lines(patch: &str, sign: char) -> Vec<usize> {
+    let mut changed = Vec::new();
+    let mut line = None;
+
+    for row in patch.lines() {
+        // "@@ -a,b +c,d @@" restarts the count at line a of the old file or c of the new
+        if let Some(hunk) = row.strip_prefix("@@ ") {
+            line = hunk
+                .split_whitespace()
+                .find_map(|part| part.strip_prefix(sign))
+                .and_then(|range| range.split(',').next())
+                .and_then(|start| start.parse::<usize>().ok());
+            continue;
+        }
+
+        // Headers of the next file in a multi-file patch
+        if row.starts_with("diff ") {
+            line = None;
+        }
+        let Some(n) = line.as_mut() else {
+            continue;
+        };
+        match row.chars().next() {
+            Some(c) if c == sign => {
+                changed.push(*n - 1);
+                *n += 1;
+            }
+            Some(' ') => *n += 1,
+            _ => {}
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combining_accents_are_zero_width() {
+        // A decomposed "é" is an "e" followed by a combining acute accent
+        let chars: Vec<char> = "e\u{0301}".chars().collect();
+        assert!(!is_zero_width(chars[0]));
+        assert!(is_zero_width(chars[1]));
+        assert!(is_zero_width('\u{20D7}'));
+        assert!(!is_zero_width('é'));
+    }
+
+    #[test]
+    fn control_chars_are_stripped_or_shown() {
+        let s = "a\x07b\tc\x7f\u{85}d\r\n";
+        assert_eq!(replace_control_chars(s, ControlChars::Strip), "ab\tcd\r\n");
+        assert_eq!(
+            replace_control_chars(s, ControlChars::Show),
+            "a\u{2407}b\tc\u{2421}\u{FFFD}d\r\n"
+        );
+        assert_eq!(replace_control_chars(s, ControlChars::Ignore), s);
+    }
 
-    // Get vertical metrics & find individual line height
-    let scale = Scale::uniform(font.size);
-    let v_metrics = font_font.v_metrics(scale);
-    let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap) * 1.2;
+    #[test]
+    fn decode_follows_whatwg_labels() {
+        let shift_jis = Encoding::for_label(b"shift_jis").unwrap();
+        assert_eq!(decode(&[0x93, 0xFA, 0x96, 0x7B], shift_jis), "日本");
 
-    line_height * num_lines as f32
+        // latin1 is windows-1252 on the web, where 0x80 is the euro sign
+        let latin1 = Encoding::for_label(b"latin1").unwrap();
+        assert_eq!(decode(&[0x80, b'5'], latin1), "€5");
+    }
 }